@@ -0,0 +1,56 @@
+//! Grows square fields until they are complete and reports the timings: with
+//! `epoch3`, with the tiled `epoch_parallel` run on one thread, and with
+//! `parallel::JOB_EPOCHS` epochs per job spread over one thread per core like the
+//! app's worker pool does.
+//! Run with `cargo bench --bench growth [-- sizes...]`, defaults to 300, 1024 and 2048.
+
+use std::thread;
+use std::time::Instant;
+
+use pixel_wfc::parallel;
+use pixel_wfc::wfc_field::{ChangeSet, WFCField};
+
+fn grow(size: usize, name: &str, step: impl Fn(&mut WFCField) -> Vec<ChangeSet>) {
+    let start = Instant::now();
+    let mut field = WFCField::new(size, size, 42);
+    let setup = start.elapsed();
+
+    let mut epochs = 0;
+    let mut cells = 0;
+    while !field.is_complete() {
+        for set in step(&mut field) {
+            cells += set.changes.len();
+            epochs += 1;
+        }
+    }
+    let total = start.elapsed();
+
+    println!(
+        "{size}x{size} {name}: setup {:.2?}, {epochs} epochs, {cells} cells in {:.2?} ({:.0} cells/s)",
+        setup,
+        total - setup,
+        cells as f64 / (total - setup).as_secs_f64()
+    );
+}
+
+fn main() {
+    let sizes: Vec<usize> = std::env::args()
+        .skip(1)
+        .filter_map(|a| a.parse().ok())
+        .collect();
+    let sizes = if sizes.is_empty() {
+        vec![300, 1024, 2048]
+    } else {
+        sizes
+    };
+
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    for size in sizes {
+        grow(size, "epoch3", |field| vec![field.epoch3()]);
+        grow(size, "parallel", |field| vec![field.epoch_parallel()]);
+        let name = format!("parallel x{}", threads);
+        grow(size, &name, |field| {
+            parallel::grow_threaded(field, parallel::JOB_EPOCHS, threads)
+        });
+    }
+}
@@ -0,0 +1,6 @@
+use yew_agent::PrivateWorker;
+use pixel_wfc::generator::Generator;
+
+fn main() {
+    Generator::register();
+}
@@ -0,0 +1,147 @@
+//! Grows a texture from a settings file and writes it out as a PNG, with the same
+//! recipe the web app loads and saves.
+//! Run with `cargo run --release --bin wfc -- recipe.toml [out.png]`, the image
+//! defaults to the settings file with a `.png` extension. Pressing enter while it
+//! grows stops early and writes what has grown so far.
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use pixel_wfc::dirty::Rect;
+use pixel_wfc::heatmap::{self, RenderMode};
+use pixel_wfc::run::{Finished, RunController};
+use pixel_wfc::settings::{Format, Settings};
+use pixel_wfc::wfc_field::WFCField;
+
+fn now_ms() -> f64 {
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    since.as_secs_f64() * 1000.0
+}
+
+fn load(path: &Path) -> Result<Settings, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let format = Format::from_path(&path.to_string_lossy()).map_err(|e| e.to_string())?;
+    Settings::parse(&text, format).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Cancels `run` once a line is entered on stdin. A closed stdin does nothing.
+fn cancel_on_enter(run: &RunController) {
+    let token = run.token();
+    thread::spawn(move || {
+        let mut line = String::new();
+        if matches!(io::stdin().read_line(&mut line), Ok(n) if n > 0) {
+            token.cancel();
+        }
+    });
+}
+
+/// Runs epochs until a stop condition is met, `None` if the run is cancelled first.
+fn grow(settings: &Settings, field: &mut WFCField, run: &mut RunController) -> Option<Finished> {
+    run.resume(field.len() - field.remaining());
+    loop {
+        if let Some(finished) = field.finished(run.elapsed_ms()) {
+            return Some(finished);
+        }
+        if run.is_cancelled() {
+            return None;
+        }
+        settings.growth.epoch(field);
+    }
+}
+
+/// The field in the settings' render mode, each cell `scale` pixels wide.
+/// Largest image written, in pixels: 1 GiB of RGBA.
+const MAX_PIXELS: usize = 1 << 28;
+
+/// Whole pixels per cell of the image for `settings`, checked against `MAX_PIXELS`
+/// before anything is grown or allocated.
+fn image_scale(settings: &Settings) -> Result<usize, String> {
+    let scale = (settings.output.scale as usize).max(1);
+    let pixels = (settings.width * scale).checked_mul(settings.height * scale);
+    match pixels {
+        Some(n) if n <= MAX_PIXELS => Ok(scale),
+        _ => Err(format!(
+            "{}x{} cells at scale {} make too large an image",
+            settings.width, settings.height, scale
+        )),
+    }
+}
+
+fn image(settings: &Settings, field: &WFCField, scale: usize) -> (usize, usize, Vec<u8>) {
+    let (w, h) = (field.width, field.height);
+    let data = match settings.output.mode {
+        RenderMode::Colour => {
+            let rect = Rect { x: 0, y: 0, w, h };
+            heatmap::colour_region(field, rect, settings.output.blank)
+        }
+        mode => heatmap::render(field, mode),
+    };
+    if scale == 1 {
+        return (w, h, data);
+    }
+
+    let mut out = Vec::with_capacity(data.len() * scale * scale);
+    for row in data.chunks_exact(w * 4) {
+        let row: Vec<u8> = row
+            .chunks_exact(4)
+            .flat_map(|px| px.repeat(scale))
+            .collect();
+        for _ in 0..scale {
+            out.extend_from_slice(&row);
+        }
+    }
+    (w * scale, h * scale, out)
+}
+
+fn write_png(path: &Path, width: usize, height: usize, data: &[u8]) -> Result<(), String> {
+    let err = |e: &dyn std::fmt::Display| format!("{}: {}", path.display(), e);
+    let file = File::create(path).map_err(|e| err(&e))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| err(&e))?;
+    writer.write_image_data(data).map_err(|e| err(&e))
+}
+
+fn run(settings_path: PathBuf, out: Option<PathBuf>) -> Result<(), String> {
+    let settings = load(&settings_path)?;
+    let out = out.unwrap_or_else(|| settings_path.with_extension("png"));
+    let scale = image_scale(&settings)?;
+
+    let mut field = settings.build();
+    let mut run = RunController::with_clock(now_ms);
+    cancel_on_enter(&run);
+    match grow(&settings, &mut field, &mut run) {
+        Some(finished) => println!(
+            "stopped at epoch {}: {}",
+            finished.epoch, finished.condition
+        ),
+        None => println!("cancelled at epoch {}", field.epoch_idx),
+    }
+
+    let (width, height, data) = image(&settings, &field, scale);
+    write_png(&out, width, height, &data)?;
+    println!("wrote {}x{} image to {}", width, height, out.display());
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let mut args = std::env::args_os().skip(1).map(PathBuf::from);
+    let Some(settings) = args.next() else {
+        eprintln!("usage: wfc <settings.toml|settings.json> [out.png]");
+        return ExitCode::FAILURE;
+    };
+    match run(settings, args.next()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
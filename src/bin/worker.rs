@@ -1,4 +1,4 @@
-use yew_agent::PublicWorker;
+use yew_agent::PrivateWorker;
 use pixel_wfc::worker::Worker;
 
 fn main() {
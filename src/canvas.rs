@@ -1,208 +1,1227 @@
 use gloo::console::log;
-use gloo::timers::callback::Timeout;
-use wasm_bindgen::Clamped;
-use std::ops::Range;
+use gloo::events::EventListener;
+use gloo::file::callbacks::{read_as_text, FileReader};
+use gloo::file::{Blob, ObjectUrl};
+use gloo::render::{request_animation_frame, AnimationFrame};
+use gloo_utils::{document, window};
 use std::rc::Rc;
-// use gloo_utils::window;
-use wasm_bindgen::JsCast;
-use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement};
-use web_sys::{HtmlInputElement, ImageData};
+use wasm_bindgen::{Clamped, JsCast};
+use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement, HtmlElement};
+use web_sys::{HtmlImageElement, HtmlInputElement, HtmlSelectElement, ImageData};
 use yew_agent::{Bridge, Bridged};
 use yew::prelude::*;
 
-use crate::types::Settings;
+use crate::generator::{Generator, BATCH_MS};
+use crate::heatmap::{self, Blank, Heatmap, RenderMode};
+use crate::selection::Selection;
+use crate::settings::{Format, Output, Settings};
+use crate::topology::TopologyKind;
+use crate::types::{Rand, Rgba};
 use crate::types::JSTimer;
-use crate::wfc_field::{Pixel, WFCField};
-use crate::worker::{Worker, WorkerInput, WorkerOutput};
+use crate::dirty::Rect;
+use crate::viewport::{Viewport, ZOOM_LEVELS};
+use crate::wfc_field::{Variation, WFCField};
+use crate::parallel::TileResult;
+use crate::run::{Finished, GrowthMode, StopCondition};
+use crate::protocol::{Command, Packed, Request, Response, Status, Update, PROTOCOL_VERSION};
+use crate::worker::Worker;
 
 
-const NUM_WORKERS:u8 = 2;
+/// Upper bound on the worker pool, which otherwise follows the number of cores.
+const MAX_WORKERS: usize = 8;
 
+// generator updates carry the whole status, messages are short-lived anyway
+#[allow(clippy::large_enum_variant)]
 pub enum Msg {
-    Draw,
-    Epochs,
-    WorkerStart,
-    WorkerMsg(WorkerOutput),
-    StartTimeout,
-    StopTimeout,
+    /// Passes a command straight on to the generator.
+    Send(Command),
+    FromGenerator(Update),
+    FromWorker(TileResult),
+    LoadImage(web_sys::File),
+    LoadSettings(web_sys::File),
+    /// Name and text of a settings file, or why it couldn't be read.
+    SettingsLoaded(String, Result<String, String>),
+    SaveSettings(Format),
+    ImageLoaded(usize, usize, Vec<u8>),
+    KeyChanged,
+    /// Outpaint by the amount in the extend input, per side as `[top, right, bottom, left]`.
+    Extend([bool; 4]),
+    /// Mouse button pressed at a screen position; `true` pans instead of selecting.
+    PointerDown(f64, f64, bool),
+    PointerMove(f64, f64),
+    PointerUp,
+    PointerLeave,
+    /// Zoom by a number of levels around a screen position.
+    Zoom(f64, f64, i32),
+    Fit,
+    ActualSize,
+    Resize,
+    SetLasso(bool),
+    Reroll,
+    JumpToEpoch,
+    SetMode(RenderMode),
+    SetBlank(Blank),
+    /// Read the settings panel back and apply what changed.
+    SettingsChanged,
+    /// Step by the number of epochs in the steps input.
+    StepN,
+    /// Per-frame time budget for growth, in ms.
+    SetSpeed(f64),
+    /// The next animation frame is due, redraw a stale heatmap and ask the generator
+    /// for another batch.
+    Frame,
+    /// Read the stop condition inputs and hand them to the generator.
+    StopConditions,
+}
+
+/// Tiles of a parallel epoch still out at the worker pool.
+struct Pending {
+    id: u64,
+    left: usize,
+    results: Vec<TileResult>,
+}
+
+/// Inputs of the settings panel.
+#[derive(Default)]
+struct SettingsForm {
+    width: NodeRef,
+    height: NodeRef,
+    seed: NodeRef,
+    topology: NodeRef,
+    growth: NodeRef,
+    /// Low and high end for hue, saturation and lightness.
+    variation: [[NodeRef; 2]; 3],
+    scale: NodeRef,
+}
+
+impl SettingsForm {
+    /// `current` with whatever parses in the inputs.
+    fn read(&self, current: &Settings) -> Settings {
+        let input = |r: &NodeRef| r.cast::<HtmlInputElement>().unwrap().value();
+        let select = |r: &NodeRef| r.cast::<HtmlSelectElement>().unwrap().value();
+        let range = |[lo, hi]: &[NodeRef; 2], cur: (f64, f64)| {
+            let lo = input(lo).parse().unwrap_or(cur.0);
+            let hi = input(hi).parse().unwrap_or(cur.1);
+            (f64::min(lo, hi), f64::max(lo, hi))
+        };
+        let [hue, saturation, lightness] = &self.variation;
+        Settings {
+            width: input(&self.width).parse().unwrap_or(current.width),
+            height: input(&self.height).parse().unwrap_or(current.height),
+            seed: input(&self.seed).parse().unwrap_or(current.seed),
+            topology: TopologyKind::from_name(&select(&self.topology)).unwrap_or(current.topology),
+            growth: GrowthMode::from_name(&select(&self.growth)).unwrap_or(current.growth),
+            variation: Variation {
+                hue: range(hue, current.variation.hue),
+                saturation: range(saturation, current.variation.saturation),
+                lightness: range(lightness, current.variation.lightness),
+            },
+            stop: current.stop.clone(),
+            output: Output {
+                scale: input(&self.scale).parse().unwrap_or(current.output.scale),
+                ..current.output.clone()
+            },
+        }
+    }
+}
+
+/// Keeps the decoding `<img>` and its object url alive until `load` fires.
+struct ImageLoader {
+    _url: ObjectUrl,
+    _img: HtmlImageElement,
+    _listener: EventListener,
 }
 
 pub struct Canvas {
     canvas: NodeRef,
     settings: Settings,
+    form: SettingsForm,
+    /// Copy of the generator's field, kept in sync from its updates down to the HSL
+    /// values, which the inspector and the expected colour preview read. Carries the
+    /// variation too, for the preview.
     field: WFCField,
+    status: Status,
     timer: JSTimer,
+    generator: Box<dyn Bridge<Generator>>,
     workers: Box<[Box<dyn Bridge<Worker>>]>,
-    timeout: Option<Timeout>,
+    steps_input: NodeRef,
+    /// Time the generator may grow for per animation frame.
+    budget_ms: f64,
+    /// Pending request for the frame that acknowledges the updates so far.
+    frame: Option<AnimationFrame>,
+    /// Updates to acknowledge on that frame.
+    acks: usize,
+    pending: Option<Pending>,
+    /// Fit the view to the next snapshot, set when importing an image.
+    fit_next: bool,
+    /// Cells an extend the generator hasn't answered yet adds on the left and top,
+    /// the view moves by them once its snapshot arrives.
+    extend_offset: Option<(usize, usize)>,
+    loader: Option<ImageLoader>,
+    settings_reader: Option<FileReader>,
+    /// Object url of the last settings download, kept until the next one.
+    download: Option<ObjectUrl>,
+    key: Option<Rgba>,
+    key_input: [NodeRef; 2],
+    extend_input: NodeRef,
+    selection: Option<Selection>,
+    selecting: bool,
+    lasso: bool,
+    jump_input: NodeRef,
+    /// Max epochs, time budget in seconds and coverage in percent, blank for none.
+    stop_inputs: [NodeRef; 3],
+    /// Why growth stopped on its own, until it changes the field again.
+    finished: Option<Finished>,
+    /// Heatmap of the field as of the last frame, for every draw until the next one.
+    heatmap: Option<Heatmap>,
+    /// The field changed since `heatmap` was made.
+    heatmap_stale: bool,
+    /// Offscreen canvas at one pixel per cell, scaled onto `canvas` when drawing.
+    buffer: HtmlCanvasElement,
+    frame_ms: f64,
+    viewport: Viewport,
+    /// Opened from a permalink, the first render keeps its scale instead of fitting.
+    linked: bool,
+    /// The field went somewhere its settings can't lead again: an import, extend or
+    /// re-roll, or growth mode or variation changed mid-run. No link is offered
+    /// until the next reset. Undo, redo and jumps leave it alone, the generator's
+    /// history restores the growth state with the cells, so growing on from there
+    /// takes the same path a fresh field does.
+    edited: bool,
+    /// Last screen position while dragging to pan.
+    pan_from: Option<(f64, f64)>,
+    /// Cell under the mouse and the screen position to show its inspector at.
+    hover: Option<(usize, f64, f64)>,
+    /// Last command the generator rejected.
+    error: Option<String>,
+    /// Why the page's link couldn't be opened, shown with the first snapshot.
+    link_error: Option<String>,
+    _resize: EventListener,
 }
 
 impl Component for Canvas {
     type Message = Msg;
     type Properties = ();
     fn create(_ctx: &Context<Self>) -> Self {
-        let settings = (300,);
-        let mut field = WFCField::new(settings.0);
-        // field.init();
+        let random = || Settings { seed: Rand::random_seed(), ..Settings::default() };
+        let (settings, linked, link_error) = match linked_settings() {
+            Some(Ok(settings)) => (settings, true, None),
+            Some(Err(e)) => (random(), false, Some(e)),
+            None => (random(), false, None),
+        };
+        // replaced by the first snapshot, the generator builds the real field
+        let field = WFCField::new(1, 1, 0);
 
-        let workers = (0..NUM_WORKERS).map(|_| {
+        let mut generator = {
+            let link = _ctx.link().clone();
+            Generator::bridge(Rc::new(move |r: Response| match r.version {
+                PROTOCOL_VERSION => link.send_message(Msg::FromGenerator(r.update)),
+                v => log!(format!("generator speaks protocol {}, expected {}", v, PROTOCOL_VERSION)),
+            }))
+        };
+        generator.send(Request::from(Command::Reset {
+            width: settings.width,
+            height: settings.height,
+            seed: settings.seed,
+            topology: settings.topology,
+        }));
+
+        let cores = window().navigator().hardware_concurrency() as usize;
+        let workers = (0..cores.clamp(1, MAX_WORKERS)).map(|_| {
             let cb = {
                 let link = _ctx.link().clone();
-                move |e| link.send_message(Self::Message::WorkerMsg(e))
+                move |e| link.send_message(Self::Message::FromWorker(e))
             };
             Worker::bridge(Rc::new(cb))
 
         }).collect::<Box<[Box<dyn Bridge<Worker>>]>>();
 
-        Self {
+        let resize = {
+            let link = _ctx.link().clone();
+            EventListener::new(&window(), "resize", move |_| link.send_message(Msg::Resize))
+        };
+
+        let mut canvas = Self {
             canvas: NodeRef::default(),
             settings,
+            form: SettingsForm::default(),
             field,
+            status: Status::default(),
             timer: JSTimer::new(),
+            generator,
             workers,
-            timeout: None
+            steps_input: NodeRef::default(),
+            budget_ms: BATCH_MS,
+            frame: None,
+            acks: 0,
+            pending: None,
+            fit_next: false,
+            extend_offset: None,
+            loader: None,
+            settings_reader: None,
+            download: None,
+            key: None,
+            key_input: Default::default(),
+            extend_input: NodeRef::default(),
+            selection: None,
+            selecting: false,
+            lasso: false,
+            jump_input: NodeRef::default(),
+            stop_inputs: Default::default(),
+            finished: None,
+            heatmap: None,
+            heatmap_stale: false,
+            buffer: document().create_element("canvas").unwrap().unchecked_into(),
+            frame_ms: 0.0,
+            viewport: Viewport::new(0.0, 0.0, 1.0),
+            linked,
+            edited: false,
+            pan_from: None,
+            hover: None,
+            error: None,
+            link_error,
+            _resize: resize,
+        };
+        if linked {
+            canvas.configure();
+            canvas.send(Command::StopWhen(canvas.settings.stop.clone()));
+            canvas.send(Command::Start);
         }
+        canvas
     }
 
     fn update(&mut self, ctx: &Context<Self>, msg: Self::Message) -> bool {
         match msg {
-            Msg::Draw => {
+            Msg::Send(cmd) => {
+                let reset = matches!(cmd, Command::Reset { .. });
+                if reset {
+                    self.edited = false;
+                }
+                self.send(cmd);
+                reset
+            }
+            Msg::FromGenerator(Update::Snapshot { width, height, topology, cells, status }) => {
+                self.field = WFCField::from_cells(width, height, status.seed, cells.0, topology);
+                self.field.epoch_idx = status.epoch;
+                self.field.set_variation(self.settings.variation);
+                self.settings.width = width;
+                self.settings.height = height;
+                self.settings.seed = status.seed;
+                self.settings.topology = topology;
+                self.status = status;
+                self.selection = None;
+                self.hover = None;
+                self.heatmap = None;
+                self.error = self.link_error.take();
+                if std::mem::take(&mut self.fit_next) {
+                    self.viewport.fit(width, height);
+                }
+                // keep the same cells on screen
+                if let Some((l, t)) = self.extend_offset.take() {
+                    self.viewport.offset.0 += l as f64;
+                    self.viewport.offset.1 += t as f64;
+                }
                 self.render_canvas();
+                self.request_frame(ctx);
+                true
+            }
+            Msg::FromGenerator(Update::Changes { changes, status }) => {
+                self.finished = None;
+                self.field.apply_writes(&changes.0);
+                self.heatmap_stale = true;
+                self.field.epoch_idx = status.epoch;
+                self.field.seed = status.seed;
+                self.status = status;
+                self.render_dirty();
+                self.request_frame(ctx);
+                true
+            }
+            Msg::FromGenerator(Update::Progress(status)) => {
+                self.field.seed = status.seed;
+                self.status = status;
+                true
+            }
+            Msg::FromGenerator(Update::Finished { finished, status }) => {
+                self.finished = Some(finished);
+                self.status = status;
+                true
+            }
+            Msg::FromGenerator(Update::Error(e)) => {
+                self.extend_offset = None;
+                self.error = Some(e.to_string());
+                true
+            }
+            Msg::FromGenerator(Update::Jobs(jobs)) => {
+                self.pending = jobs.first().map(|j| Pending {
+                    id: j.id,
+                    left: jobs.len(),
+                    results: Vec::with_capacity(jobs.len()),
+                });
+                for (i, job) in jobs.into_iter().enumerate() {
+                    self.workers[i % self.workers.len()].send(job);
+                }
                 false
-            
             }
-            Msg::Epochs => {
-                // log!("Epochs start");
-                // self.timer.start_time();
-                // self.field.epoch();
-                // self.timer.epoch_from_start("Epoch took");
-
-                // log!("Epochs start");
-                // self.timer.start_time();
-                self.field.epoch3();
-                // self.timer.epoch_from_start("Epoch took");
-
-                // self.start_epoch();
-
-                // self.timer.start_time();
-                ctx.link().send_message(Msg::Draw);
-                // self.timer.epoch_from_start("Draw took");
-                ctx.link().send_message(Msg::StartTimeout);
+            Msg::FromWorker(result) => {
+                let Some(pending) = self.pending.as_mut().filter(|p| p.id == result.id) else {
+                    // from an epoch that was overtaken by newer jobs
+                    return false;
+                };
+                pending.results.push(result);
+                pending.left -= 1;
+                if pending.left == 0 {
+                    let results = self.pending.take().unwrap().results;
+                    self.send(Command::Tiles(results));
+                }
                 false
             }
-            Msg::WorkerStart => {
-                // self.worker.send(WorkerInput {
-                //     n: 5 as u32,
-                // });
+            Msg::LoadImage(file) => {
+                self.load_image(ctx, file);
                 false
             }
-            Msg::WorkerMsg(v) => {
-                log!(format!("Fibonacci value: {}", v.value));
+            Msg::ImageLoaded(width, height, rgba) => {
+                self.loader = None;
+                self.fit_next = true;
+                self.edited = true;
+                self.send(Command::Import {
+                    width,
+                    height,
+                    rgba: Packed(rgba),
+                    key: self.key,
+                    seed: Rand::random_seed(),
+                    topology: self.settings.topology,
+                });
                 false
             }
-            Msg::StartTimeout => {
-                let handle = {
-                    let link = ctx.link().clone();
-                    Timeout::new(10, move || link.send_message(Msg::Epochs))
-                };
-                self.timeout = Some(handle);
-
+            Msg::KeyChanged => {
+                let enabled = self.key_input[0].cast::<HtmlInputElement>().unwrap();
+                let colour = self.key_input[1].cast::<HtmlInputElement>().unwrap();
+                self.key = Some(colour.value())
+                    .filter(|_| enabled.checked())
+                    .and_then(|v| parse_hex(&v));
+                false
+            }
+            Msg::Extend(sides) => {
+                let input = self.extend_input.cast::<HtmlInputElement>().unwrap();
+                let n = input.value().parse::<usize>().unwrap_or(0);
+                let [t, r, b, l] = sides.map(|s| if s { n } else { 0 });
+                if t + r + b + l > 0 {
+                    self.send(Command::Extend([t, r, b, l]));
+                    self.extend_offset = Some((l, t));
+                    self.edited = true;
+                }
+                t + r + b + l > 0
+            }
+            Msg::PointerDown(sx, sy, true) => {
+                self.pan_from = Some((sx, sy));
                 false
             }
-            Msg::StopTimeout => {
-                self.timeout = None;
+            Msg::PointerDown(sx, sy, false) => {
+                let (x, y) = self.viewport.to_cell((sx, sy));
+                self.selecting = true;
+                self.selection = Some(match self.lasso {
+                    true => Selection::Lasso(vec![(x, y)]),
+                    false => Selection::Rect((x, y), (x, y)),
+                });
+                false
+            }
+            Msg::PointerMove(sx, sy) => {
+                if let Some((px, py)) = self.pan_from {
+                    self.viewport.pan(sx - px, sy - py);
+                    self.pan_from = Some((sx, sy));
+                    self.render_canvas();
+                } else if let (true, Some(sel)) = (self.selecting, self.selection.as_mut()) {
+                    sel.push(self.viewport.to_cell((sx, sy)));
+                    self.render_canvas();
+                }
+                let (x, y) = self.viewport.to_cell((sx, sy));
+                let (w, h) = (self.field.width as f64, self.field.height as f64);
+                let inside = x >= 0.0 && y >= 0.0 && x < w && y < h;
+                let hover = inside.then(|| (self.field.idx((x as usize, y as usize)), sx, sy));
+                let changed = hover.map(|h| h.0) != self.hover.map(|h| h.0);
+                self.hover = hover;
+                changed
+            }
+            Msg::PointerUp => {
+                self.selecting = false;
+                self.pan_from = None;
                 false
             }
+            Msg::PointerLeave => {
+                self.selecting = false;
+                self.pan_from = None;
+                self.hover.take().is_some()
+            }
+            Msg::Zoom(sx, sy, steps) => {
+                self.viewport.zoom_at((sx, sy), steps);
+                self.render_canvas();
+                true
+            }
+            Msg::Fit => {
+                self.viewport.fit(self.field.width, self.field.height);
+                self.render_canvas();
+                true
+            }
+            Msg::ActualSize => {
+                let centre = (self.viewport.width / 2.0, self.viewport.height / 2.0);
+                self.viewport.zoom_to(centre, 1.0);
+                self.render_canvas();
+                true
+            }
+            Msg::Resize => {
+                self.resize();
+                self.render_canvas();
+                false
+            }
+            Msg::SetMode(mode) => {
+                self.settings.output.mode = mode;
+                self.heatmap = None;
+                self.render_canvas();
+                false
+            }
+            Msg::SetBlank(blank) => {
+                self.settings.output.blank = blank;
+                self.render_canvas();
+                false
+            }
+            Msg::SettingsChanged => {
+                let next = self.form.read(&self.settings);
+                self.apply_settings(next);
+                true
+            }
+            Msg::LoadSettings(file) => {
+                let link = ctx.link().clone();
+                let name = file.name();
+                let file = gloo::file::File::from(file);
+                self.settings_reader = Some(read_as_text(&file, move |text| {
+                    link.send_message(Msg::SettingsLoaded(name, text.map_err(|e| e.to_string())))
+                }));
+                false
+            }
+            Msg::SettingsLoaded(name, text) => {
+                self.settings_reader = None;
+                let settings = text
+                    .map_err(|e| format!("{}: {}", name, e))
+                    .and_then(|text| {
+                        let format = Format::from_path(&name).map_err(|e| e.to_string())?;
+                        Settings::parse(&text, format).map_err(|e| e.to_string())
+                    });
+                match settings {
+                    Ok(settings) => self.apply_settings(settings),
+                    Err(e) => self.error = Some(e),
+                }
+                true
+            }
+            Msg::SaveSettings(format) => {
+                self.save_settings(format);
+                true
+            }
+            Msg::StepN => {
+                let input = self.steps_input.cast::<HtmlInputElement>().unwrap();
+                if let Ok(epochs) = input.value().parse::<usize>() {
+                    self.send(Command::Step(epochs));
+                }
+                false
+            }
+            Msg::SetSpeed(budget_ms) => {
+                self.budget_ms = budget_ms;
+                self.configure();
+                true
+            }
+            Msg::Frame => {
+                self.frame = None;
+                if std::mem::take(&mut self.heatmap_stale) && self.heatmap.take().is_some() {
+                    self.render_canvas();
+                }
+                for _ in 0..std::mem::take(&mut self.acks) {
+                    self.send(Command::Ack);
+                }
+                false
+            }
+            Msg::SetLasso(lasso) => {
+                self.lasso = lasso;
+                false
+            }
+            Msg::Reroll => {
+                if let Some(selection) = self.selection.take() {
+                    let seed = Rand::random_seed();
+                    self.send(Command::Reroll { selection, seed });
+                    self.edited = true;
+                    self.render_canvas();
+                }
+                true
+            }
+            Msg::JumpToEpoch => {
+                let input = self.jump_input.cast::<HtmlInputElement>().unwrap();
+                if let Ok(epoch) = input.value().parse::<usize>() {
+                    self.send(Command::JumpTo(epoch));
+                }
+                false
+            }
+            Msg::StopConditions => {
+                let value = |i: usize| {
+                    let input = self.stop_inputs[i].cast::<HtmlInputElement>().unwrap();
+                    input.value().parse::<f64>().ok().filter(|v| *v > 0.0)
+                };
+                // the inputs only cover the conditions with a value, keep the others
+                let mut stop: Vec<_> = self.settings.stop.iter().copied().filter(|c| {
+                    matches!(c, StopCondition::AllCollapsed | StopCondition::FrontierEmpty)
+                }).collect();
+                stop.extend(value(0).map(|v| StopCondition::MaxEpochs(v as usize)));
+                stop.extend(value(1).map(|v| StopCondition::TimeBudget(v * 1000.0)));
+                stop.extend(value(2).map(|v| StopCondition::Coverage((v / 100.0).min(1.0))));
+                let mut next = Settings { stop, ..self.settings.clone() };
+                // the zoom the view is at, so applying doesn't snap it back
+                next.output.scale = self.viewport.zoom;
+                self.apply_settings(next);
+                true
+            }
         }
     }
 
     fn view(&self, ctx: &Context<Self>) -> Html {
-        let onclick = ctx.link().batch_callback(move |_| vec![Msg::Epochs]);
-        let onclick2 = ctx.link().callback(move |_| Msg::StopTimeout);
-        ctx.link().send_message(Msg::Draw);
+        let send = |cmd: fn() -> Command| ctx.link().callback(move |_| Msg::Send(cmd()));
+        let busy = self.status.running || self.status.steps > 0;
+        let (on_toggle, toggle) = match busy {
+            true => (send(|| Command::Pause), "Pause"),
+            false if self.status.epoch == 0 || self.status.cancelled => (send(|| Command::Start), "Start"),
+            false => (send(|| Command::Start), "Resume"),
+        };
+        let on_cancel = send(|| Command::Cancel);
+        let on_step = send(|| Command::Step(1));
+        let on_step_n = ctx.link().callback(|_| Msg::StepN);
+        let reset = |seed: Option<u64>| {
+            let Settings { width, height, topology, .. } = self.settings;
+            ctx.link().callback(move |_| {
+                let seed = seed.unwrap_or_else(Rand::random_seed);
+                Msg::Send(Command::Reset { width, height, seed, topology })
+            })
+        };
+        let on_reset = reset(Some(self.settings.seed));
+        let on_new_seed = reset(None);
+        let on_file = ctx.link().batch_callback(|e: Event| {
+            let input: HtmlInputElement = e.target_unchecked_into();
+            let file = input.files().and_then(|f| f.get(0));
+            file.map(Msg::LoadImage)
+        });
+        let on_key = ctx.link().callback(|_: Event| Msg::KeyChanged);
+        let on_extend = |sides: [bool; 4]| ctx.link().callback(move |_| Msg::Extend(sides));
+        // middle or right button, or shift with the left one, drags the view
+        let on_down = ctx.link().callback(|e: MouseEvent| {
+            let pan = e.button() != 0 || e.shift_key();
+            Msg::PointerDown(e.offset_x() as f64, e.offset_y() as f64, pan)
+        });
+        let on_move = ctx.link().callback(|e: MouseEvent| {
+            Msg::PointerMove(e.offset_x() as f64, e.offset_y() as f64)
+        });
+        let on_up = ctx.link().callback(|_: MouseEvent| Msg::PointerUp);
+        let on_leave = ctx.link().callback(|_: MouseEvent| Msg::PointerLeave);
+        let on_wheel = ctx.link().callback(|e: WheelEvent| {
+            e.prevent_default();
+            let steps = if e.delta_y() < 0.0 { 1 } else { -1 };
+            Msg::Zoom(e.offset_x() as f64, e.offset_y() as f64, steps)
+        });
+        let on_menu = Callback::from(|e: MouseEvent| e.prevent_default());
+        let on_fit = ctx.link().callback(|_| Msg::Fit);
+        let on_actual = ctx.link().callback(|_| Msg::ActualSize);
+        let on_mode = ctx.link().batch_callback(|e: Event| {
+            let select: HtmlSelectElement = e.target_unchecked_into();
+            RenderMode::from_name(&select.value()).map(Msg::SetMode)
+        });
+        let on_blank = ctx.link().batch_callback(|e: Event| {
+            let select: HtmlSelectElement = e.target_unchecked_into();
+            Blank::from_name(&select.value()).map(Msg::SetBlank)
+        });
+        let on_lasso = ctx.link().callback(|e: Event| {
+            let input: HtmlInputElement = e.target_unchecked_into();
+            Msg::SetLasso(input.checked())
+        });
+        let on_reroll = ctx.link().callback(|_| Msg::Reroll);
+        let on_undo = send(|| Command::Undo);
+        let on_redo = send(|| Command::Redo);
+        let on_jump = ctx.link().callback(|_| Msg::JumpToEpoch);
+        let on_speed = ctx.link().callback(|e: InputEvent| {
+            let input: HtmlInputElement = e.target_unchecked_into();
+            Msg::SetSpeed(input.value_as_number())
+        });
+        let speed = match self.budget_ms {
+            ms if ms > 0.0 => format!(" {} ms/frame", ms),
+            _ => " 1 epoch/frame".to_string(),
+        };
+        let on_stop = ctx.link().callback(|_: Event| Msg::StopConditions);
+        let stop_value = |f: fn(&StopCondition) -> Option<f64>| {
+            self.settings.stop.iter().find_map(f).map_or(String::new(), |v| v.to_string())
+        };
+        let stop_epoch = stop_value(|c| match c {
+            StopCondition::MaxEpochs(epochs) => Some(*epochs as f64),
+            _ => None,
+        });
+        let stop_time = stop_value(|c| match c {
+            StopCondition::TimeBudget(ms) => Some(ms / 1000.0),
+            _ => None,
+        });
+        let stop_coverage = stop_value(|c| match c {
+            StopCondition::Coverage(share) => Some(share * 100.0),
+            _ => None,
+        });
         html! {
             <div>
-                <button onclick={&onclick}>{"Start"}</button>
-                <button onclick={&onclick2}>{"Stop"}</button>
-                // <div>
-                //     <label for="upper">{"Threshold"}
-                //     <input type="range" min="0" max="256" class="slider" id="upper" onchange={&on_change} ref={self.input[0].clone()}/>
-                //     </label>
-                // </div>
-                // <div>
-                //     <label for="vertical">{"Vertical"}
-                //     <input type="checkbox" id="vertical" onchange={&on_change} ref={self.input[1].clone()}/>
-                //     </label>
-                // </div>
-
+                <button onclick={&on_toggle}>{toggle}</button>
+                <button onclick={&on_step} disabled={busy}>{"Step"}</button>
+                <input type="number" min="1" value="10" id="steps" ref={self.steps_input.clone()}/>
+                <button onclick={&on_step_n} disabled={busy}>{"Step N"}</button>
+                <button onclick={&on_reset}>{"Reset"}</button>
+                <button onclick={&on_new_seed}>{"New seed"}</button>
+                if self.edited {
+                    <span title="Imports, extends, re-rolls and growth changes mid-run can't be linked, reset to get a link again">
+                        {" Link (edited)"}
+                    </span>
+                } else {
+                    <a href={self.permalink()} title="Grows this field again when opened">
+                        {" Link"}
+                    </a>
+                }
+                <label for="speed">{" Speed"}
+                <input type="range" min="0" max="50" step="1" id="speed"
+                    value={self.budget_ms.to_string()} oninput={&on_speed}/>
+                </label>
+                <span>{speed}</span>
+                { self.progress_bar(on_cancel) }
+                { self.settings_panel(ctx) }
+                <div>
+                    {"Stop at "}
+                    <label for="stop-epoch">{"epoch"}
+                    <input type="number" min="1" id="stop-epoch" value={stop_epoch} onchange={&on_stop} ref={self.stop_inputs[0].clone()}/>
+                    </label>
+                    <label for="stop-time">{" time (s)"}
+                    <input type="number" min="0" id="stop-time" value={stop_time} onchange={&on_stop} ref={self.stop_inputs[1].clone()}/>
+                    </label>
+                    <label for="stop-coverage">{" coverage (%)"}
+                    <input type="number" min="0" max="100" id="stop-coverage" value={stop_coverage} onchange={&on_stop} ref={self.stop_inputs[2].clone()}/>
+                    </label>
+                    if let Some(f) = &self.finished {
+                        <span>{format!(" Finished at epoch {}: {}", f.epoch, f.condition)}</span>
+                    } else if self.status.cancelled {
+                        <span>{format!(" Cancelled at epoch {}", self.status.epoch)}</span>
+                    }
+                </div>
+                if let Some(error) = &self.error {
+                    <span style="color: red;">{format!(" {}", error)}</span>
+                }
+                <div>
+                    <label for="inpaint">{"Inpaint image"}
+                    <input type="file" accept="image/*" id="inpaint" onchange={&on_file}/>
+                    </label>
+                    <label for="use-key">{"Key colour"}
+                    <input type="checkbox" id="use-key" onchange={&on_key} ref={self.key_input[0].clone()}/>
+                    </label>
+                    <input type="color" value="#ff00ff" onchange={&on_key} ref={self.key_input[1].clone()}/>
+                </div>
                 <div>
+                    <label for="extend">{"Extend by"}
+                    <input type="number" min="1" value="20" id="extend" ref={self.extend_input.clone()}/>
+                    </label>
+                    <button onclick={on_extend([true, false, false, false])}>{"Top"}</button>
+                    <button onclick={on_extend([false, true, false, false])}>{"Right"}</button>
+                    <button onclick={on_extend([false, false, true, false])}>{"Bottom"}</button>
+                    <button onclick={on_extend([false, false, false, true])}>{"Left"}</button>
+                    <button onclick={on_extend([true; 4])}>{"All"}</button>
+                </div>
+                <div>
+                    <label for="lasso">{"Lasso"}
+                    <input type="checkbox" id="lasso" checked={self.lasso} onchange={&on_lasso}/>
+                    </label>
+                    <button onclick={&on_reroll}>{"Re-roll selection"}</button>
+                    <span>{format!("Seed: {}", self.field.seed)}</span>
+                </div>
+                <div>
+                    <button onclick={&on_undo} disabled={!self.status.can_undo}>{"Undo"}</button>
+                    <button onclick={&on_redo} disabled={!self.status.can_redo}>{"Redo"}</button>
+                    <label for="jump">{"Epoch"}
+                    <input type="number" min={self.status.first_epoch.unwrap_or(0).to_string()}
+                        value={self.field.epoch_idx.to_string()} id="jump" ref={self.jump_input.clone()}/>
+                    </label>
+                    <button onclick={&on_jump}>{"Jump"}</button>
+                    <span>{format!("History: {:.1} MiB", self.status.history_bytes as f64 / (1 << 20) as f64)}</span>
+                    <span>{format!(" Frame: {:.1} ms", self.frame_ms)}</span>
+                </div>
+                <div>
+                    <button onclick={&on_fit}>{"Fit"}</button>
+                    <button onclick={&on_actual}>{"1:1"}</button>
+                    <span>{format!(" Zoom: {}x", self.viewport.zoom)}</span>
+                    <label for="mode">{" Show "}
+                    <select id="mode" onchange={&on_mode}>
+                        { for RenderMode::ALL.iter().map(|m| html! {
+                            <option value={m.name()} selected={*m == self.settings.output.mode}>{m.name()}</option>
+                        }) }
+                    </select>
+                    </label>
+                    <label for="blank">{" Uncollapsed "}
+                    <select id="blank" onchange={&on_blank}>
+                        { for Blank::ALL.iter().map(|b| html! {
+                            <option value={b.name()} selected={*b == self.settings.output.blank}>{b.name()}</option>
+                        }) }
+                    </select>
+                    </label>
+                </div>
+                <div style="position: relative;">
                     <canvas
                         id="canvas"
-                        width={1000}
-                        height={1000}
+                        onmousedown={&on_down}
+                        onmousemove={&on_move}
+                        onmouseup={&on_up}
+                        onmouseleave={&on_leave}
+                        onwheel={&on_wheel}
+                        oncontextmenu={&on_menu}
                         ref={self.canvas.clone()}>
                     </canvas>
+                    { self.inspector() }
                 </div>
             </div>
         }
     }
+
+    fn rendered(&mut self, _ctx: &Context<Self>, first_render: bool) {
+        if first_render {
+            self.resize();
+            self.viewport.fit(self.field.width, self.field.height);
+            if self.linked {
+                let centre = (self.viewport.width / 2.0, self.viewport.height / 2.0);
+                let (min, max) = (ZOOM_LEVELS[0], ZOOM_LEVELS[ZOOM_LEVELS.len() - 1]);
+                self.viewport.zoom_to(centre, self.settings.output.scale.clamp(min, max));
+            }
+            self.render_canvas();
+        }
+    }
 }
 
 impl Canvas {
-    fn start_epoch(&mut self) {
-        if self.field.epoch_idx < 10 {
-            self.field.epoch();
+    fn send(&mut self, cmd: Command) {
+        self.generator.send(Request::from(cmd));
+    }
+
+    /// Switches to `next`, building a new field if its size, seed or neighbourhood
+    /// changed and passing everything else on to the running one. Invalid settings
+    /// are shown as an error and leave everything as it was.
+    fn apply_settings(&mut self, next: Settings) {
+        if let Err(e) = next.validate() {
+            self.error = Some(e.to_string());
+            return;
+        }
+        self.error = None;
+        let rebuild = self.settings.rebuilds(&next);
+        let regrow = next.growth != self.settings.growth || next.variation != self.settings.variation;
+        match rebuild {
+            true => self.edited = false,
+            false => self.edited |= regrow && self.status.epoch > 0,
+        }
+        let rescale = next.output.scale != self.viewport.zoom;
+        let restop = next.stop != self.settings.stop;
+        self.settings = next;
+        self.field.set_variation(self.settings.variation);
+        self.configure();
+        if restop {
+            self.send(Command::StopWhen(self.settings.stop.clone()));
+        }
+        if rebuild {
+            self.fit_next = !rescale;
+            let Settings { width, height, seed, topology, .. } = self.settings;
+            self.send(Command::Reset { width, height, seed, topology });
+        }
+        if rescale {
+            let centre = (self.viewport.width / 2.0, self.viewport.height / 2.0);
+            let (min, max) = (ZOOM_LEVELS[0], ZOOM_LEVELS[ZOOM_LEVELS.len() - 1]);
+            self.viewport.zoom_to(centre, self.settings.output.scale.clamp(min, max));
+        }
+        self.render_canvas();
+    }
+
+    /// Page url with the settings in its fragment. The field stops at its current
+    /// epoch instead of after some time, so the link shows the same cells wherever
+    /// it's opened, however fast the machine.
+    fn permalink(&self) -> String {
+        let mut settings = self.settings.clone();
+        settings.output.scale = self.viewport.zoom;
+        let timed = |c: &StopCondition| {
+            matches!(c, StopCondition::MaxEpochs(_) | StopCondition::TimeBudget(_))
+        };
+        settings.stop.retain(|c| !timed(c));
+        settings.stop.push(StopCondition::MaxEpochs(self.status.epoch));
+        let location = window().location();
+        let page = location.href().unwrap_or_default();
+        let page = page.split('#').next().unwrap_or_default();
+        match settings.to_fragment() {
+            Ok(json) => format!("{}#{}", page, js_sys::encode_uri_component(&json)),
+            Err(_) => page.to_string(),
+        }
+    }
+
+    /// Offers the current settings as a file download.
+    fn save_settings(&mut self, format: Format) {
+        self.settings.output.scale = self.viewport.zoom;
+        let text = match self.settings.to_text(format) {
+            Ok(text) => text,
+            Err(e) => return self.error = Some(e.to_string()),
+        };
+        let url = ObjectUrl::from(Blob::new(text.as_str()));
+        let a: HtmlElement = document().create_element("a").unwrap().unchecked_into();
+        a.set_attribute("href", &url).unwrap();
+        a.set_attribute("download", &format!("pixel_wfc.{}", format.extension())).unwrap();
+        a.click();
+        self.download = Some(url);
+    }
+
+    fn configure(&mut self) {
+        self.send(Command::Configure {
+            mode: self.settings.growth,
+            budget_ms: self.budget_ms,
+            variation: self.settings.variation,
+        });
+    }
+
+    /// Acknowledges the last update on the next animation frame, so the generator
+    /// sends at most one batch per frame.
+    fn request_frame(&mut self, ctx: &Context<Self>) {
+        self.acks += 1;
+        if self.frame.is_none() {
+            let link = ctx.link().clone();
+            self.frame = Some(request_animation_frame(move |_| link.send_message(Msg::Frame)));
         }
+    }
+
+    /// Field and growth settings, applied as soon as an input changes.
+    fn settings_panel(&self, ctx: &Context<Self>) -> Html {
+        let on_change = ctx.link().callback(|_: Event| Msg::SettingsChanged);
+        let on_load = ctx.link().batch_callback(|e: Event| {
+            let input: HtmlInputElement = e.target_unchecked_into();
+            let file = input.files().and_then(|f| f.get(0));
+            file.map(Msg::LoadSettings)
+        });
+        let on_save = |format: Format| ctx.link().callback(move |_| Msg::SaveSettings(format));
+        let s = &self.settings;
+        let form = &self.form;
+        let growth_label = |m: GrowthMode| match m {
+            GrowthMode::Parallel => format!("{} ({} workers, slower)", m.name(), self.workers.len()),
+            _ => m.name().to_string(),
+        };
+        let range = |id: &str, refs: &[NodeRef; 2], (lo, hi): (f64, f64), step: &str| html! {
+            <label for={format!("{}-lo", id)}>{format!(" {} ", id)}
+            <input type="number" step={step.to_string()} id={format!("{}-lo", id)} value={lo.to_string()}
+                onchange={&on_change} ref={refs[0].clone()}/>
+            {" to "}
+            <input type="number" step={step.to_string()} value={hi.to_string()}
+                onchange={&on_change} ref={refs[1].clone()}/>
+            </label>
+        };
+        html! {
+            <details>
+                <summary>{"Settings"}</summary>
+                <div>
+                    <label for="load-settings">{"Load "}
+                    <input type="file" accept=".toml,.json" id="load-settings" onchange={on_load}/>
+                    </label>
+                    <button onclick={on_save(Format::Toml)}>{"Save TOML"}</button>
+                    <button onclick={on_save(Format::Json)}>{"Save JSON"}</button>
+                </div>
+                <div>
+                    <label for="width">{"Size "}
+                    <input type="number" min="1" id="width" value={s.width.to_string()}
+                        onchange={&on_change} ref={form.width.clone()}/>
+                    </label>
+                    {" x "}
+                    <input type="number" min="1" value={s.height.to_string()}
+                        onchange={&on_change} ref={form.height.clone()}/>
+                    <label for="seed">{" Seed "}
+                    <input type="number" min="0" id="seed" value={s.seed.to_string()}
+                        onchange={&on_change} ref={form.seed.clone()}/>
+                    </label>
+                    <label for="topology">{" Neighbourhood "}
+                    <select id="topology" onchange={&on_change} ref={form.topology.clone()}>
+                        { for TopologyKind::ALL.iter().map(|t| html! {
+                            <option value={t.name()} selected={*t == s.topology}>{t.name()}</option>
+                        }) }
+                    </select>
+                    </label>
+                </div>
+                <div>
+                    <label for="growth">{"Growth "}
+                    <select id="growth" onchange={&on_change} ref={form.growth.clone()}>
+                        { for GrowthMode::ALL.iter().map(|m| html! {
+                            <option value={m.name()} selected={*m == s.growth}>{growth_label(*m)}</option>
+                        }) }
+                    </select>
+                    </label>
+                    <label for="scale">{" Scale "}
+                    <input type="number" step="any" id="scale" min={ZOOM_LEVELS[0].to_string()}
+                        max={ZOOM_LEVELS[ZOOM_LEVELS.len() - 1].to_string()}
+                        value={self.viewport.zoom.to_string()} onchange={&on_change} ref={form.scale.clone()}/>
+                    </label>
+                </div>
+                <div>
+                    {"Variation"}
+                    { range("hue", &form.variation[0], s.variation.hue, "1") }
+                    { range("saturation", &form.variation[1], s.variation.saturation, "0.01") }
+                    { range("lightness", &form.variation[2], s.variation.lightness, "0.01") }
+                </div>
+            </details>
+        }
+    }
+
+    /// Collapsed cells out of all of them, with the frontier size and timings.
+    fn progress_bar(&self, on_cancel: Callback<MouseEvent>) -> Html {
+        let p = &self.status.progress;
+        let busy = self.status.running || self.status.steps > 0;
+        let eta = match p.eta_ms {
+            Some(ms) => duration(ms),
+            None => "-".to_string(),
+        };
+        html! {
+            <div>
+                <progress max={p.total.to_string()} value={p.collapsed.to_string()}/>
+                <span>{format!(
+                    " {:.1}% ({}/{}), frontier {}, elapsed {}, ETA {} ",
+                    p.fraction() * 100.0, p.collapsed, p.total, p.frontier, duration(p.elapsed_ms), eta
+                )}</span>
+                <button onclick={on_cancel} disabled={!busy}>{"Cancel"}</button>
+            </div>
+        }
+    }
+
+    /// Decodes `file` through an `<img>` and reads its pixels back from a scratch canvas.
+    fn load_image(&mut self, ctx: &Context<Self>, file: web_sys::File) {
+        let url = ObjectUrl::from(gloo::file::File::from(file));
+        let img = HtmlImageElement::new().unwrap();
+
+        let listener = {
+            let img = img.clone();
+            let link = ctx.link().clone();
+            EventListener::once(&img.clone(), "load", move |_| {
+                let (w, h) = (img.natural_width(), img.natural_height());
+                let canvas: HtmlCanvasElement =
+                    document().create_element("canvas").unwrap().unchecked_into();
+                canvas.set_width(w);
+                canvas.set_height(h);
+                let ctxx: CanvasRenderingContext2d =
+                    canvas.get_context("2d").unwrap().unwrap().unchecked_into();
+                ctxx.draw_image_with_html_image_element(&img, 0.0, 0.0).unwrap();
+                let data = ctxx
+                    .get_image_data(0.0, 0.0, w as f64, h as f64)
+                    .unwrap()
+                    .data();
+                link.send_message(Msg::ImageLoaded(w as usize, h as usize, data.0));
+            })
+        };
+        img.set_src(&url);
 
-        // get visited length
-        // split into equal parts by NUM_WORKERS
-        // pass visited into worker
-        // 
+        self.loader = Some(ImageLoader {
+            _url: url,
+            _img: img,
+            _listener: listener,
+        });
     }
 
-    fn render_canvas(&self) {
+    /// Tooltip describing the hovered cell and how its colour came about.
+    fn inspector(&self) -> Html {
+        let Some((idx, sx, sy)) = self.hover else {
+            return html! {};
+        };
+        let (x, y) = self.field.pos(idx);
+        let (px, collapsed, origin) = self.field.inspect(idx);
+        let [r, g, b, a] = px.rgba;
+        let [hue, sat, light] = px.hsl;
+
+        let state = match (collapsed, origin.epoch) {
+            (false, _) => "uncollapsed".to_string(),
+            (true, None) => "imported".to_string(),
+            (true, Some(e)) if origin.sources == 0 => format!("seeded in epoch {}", e),
+            (true, Some(e)) => format!("collapsed in epoch {} (#{})", e, origin.order),
+        };
+        let sources = origin.source_offsets().filter_map(|(dx, dy)| {
+            let nx = usize::try_from(x as i64 + dx).ok()?;
+            let ny = usize::try_from(y as i64 + dy).ok()?;
+            let [h, s, l] = self.field.cells.hsl(self.field.idx((nx, ny)));
+            let line = format!(
+                "  {} ({}, {}) hsl({:.0}, {:.2}, {:.2})",
+                direction(dx, dy), nx, ny, h, s, l,
+            );
+            Some(html! { <div>{line}</div> })
+        });
+
+        let style = format!(
+            "position: absolute; left: {}px; top: {}px; pointer-events: none; \
+             background: rgba(0, 0, 0, 0.8); color: white; padding: 4px; \
+             font: 12px monospace; white-space: pre;",
+            sx + 16.0, sy + 16.0,
+        );
+        html! {
+            <div style={style}>
+                <div>{format!("({}, {})", x, y)}</div>
+                <div>{format!("rgba({}, {}, {}, {})", r, g, b, a)}</div>
+                <div>{format!("hsl({:.0}, {:.2}, {:.2})", hue, sat, light)}</div>
+                <div>{state}</div>
+                if origin.sources != 0 {
+                    <div>{"from:"}</div>
+                    { for sources }
+                }
+            </div>
+        }
+    }
+
+    /// Sizes the canvas to fill the window below the controls.
+    fn resize(&mut self) {
+        let canvas: HtmlCanvasElement = self.canvas.cast().unwrap();
+        let bounds = canvas.get_bounding_client_rect();
+        let inner = |v: Result<wasm_bindgen::JsValue, _>| {
+            v.ok().and_then(|v| v.as_f64()).unwrap_or(0.0)
+        };
+        let w = (inner(window().inner_width()) - 2.0 * bounds.left()).max(1.0);
+        // a few pixels short so the page doesn't grow a scrollbar
+        let h = (inner(window().inner_height()) - bounds.top() - 8.0).max(1.0);
+        canvas.set_width(w as u32);
+        canvas.set_height(h as u32);
+        self.viewport.resize(w.floor(), h.floor());
+    }
+
+    fn contexts(&self) -> (CanvasRenderingContext2d, CanvasRenderingContext2d) {
         let canvas: HtmlCanvasElement = self.canvas.cast().unwrap();
         let ctxx: CanvasRenderingContext2d =
             canvas.get_context("2d").unwrap().unwrap().unchecked_into();
+        let bctx: CanvasRenderingContext2d =
+            self.buffer.get_context("2d").unwrap().unwrap().unchecked_into();
+        ctxx.set_image_smoothing_enabled(false);
+        (ctxx, bctx)
+    }
+
+    /// Copies `data`, the RGBA bytes of `rect`, into the offscreen `buffer` canvas (one
+    /// pixel per cell) and scales it onto the visible one through the viewport,
+    /// smoothing off so cells stay crisp.
+    fn blit(
+        &self,
+        ctxx: &CanvasRenderingContext2d,
+        bctx: &CanvasRenderingContext2d,
+        r: Rect,
+        data: &[u8],
+    ) {
+        let img =
+            ImageData::new_with_u8_clamped_array_and_sh(Clamped(data), r.w as u32, r.h as u32)
+                .unwrap();
+        bctx.put_image_data(&img, r.x as f64, r.y as f64).unwrap();
+
+        let (x, y, w, h) = (r.x as f64, r.y as f64, r.w as f64, r.h as f64);
+        // both corners are rounded, so neighbouring rects meet without seams
+        let (x0, y0) = self.viewport.to_screen((x, y));
+        let (x1, y1) = self.viewport.to_screen((x + w, y + h));
+        ctxx.draw_image_with_html_canvas_element_and_sw_and_sh_and_dx_and_dy_and_dw_and_dh(
+            &self.buffer, x, y, w, h, x0, y0, x1 - x0, y1 - y0,
+        )
+        .unwrap();
+    }
+
+    fn render_canvas(&mut self) {
+        self.timer.start_time();
+        let (w, h) = (self.field.width as u32, self.field.height as u32);
+        if self.buffer.width() != w || self.buffer.height() != h {
+            self.buffer.set_width(w);
+            self.buffer.set_height(h);
+        }
+        self.field.take_dirty();
+
+        let (ctxx, bctx) = self.contexts();
+        let canvas = ctxx.canvas().unwrap();
+        ctxx.clear_rect(0.0, 0.0, canvas.width() as f64, canvas.height() as f64);
+        if let Some(visible) = self.viewport.visible(w as usize, h as usize) {
+            let data = match self.settings.output.mode {
+                RenderMode::Colour => heatmap::colour_region(&self.field, visible, self.settings.output.blank),
+                mode => {
+                    if self.heatmap.as_ref().map(|h| h.mode()) != Some(mode) {
+                        self.heatmap = Some(Heatmap::new(&self.field, mode));
+                    }
+                    self.heatmap.as_ref().unwrap().region(&self.field, visible)
+                }
+            };
+            self.blit(&ctxx, &bctx, visible, &data);
+        }
 
-        let scale = 3;
-        let minus = 0;
-        ctxx.clear_rect(0.0, 0.0, 1000.0, 1000.0);
-        for x in 0..self.field.dim {
-            for y in 0..self.field.dim {
-                let cl = &self.field.data[x * self.field.dim + y];
-                let px = &cl.px;
-                let cd = format!(
-                    "rgba({},{},{},{})",
-                    px.rgba[0], px.rgba[1], px.rgba[2], px.rgba[3]
-                );
-
-                ctxx.set_fill_style(&cd.into());
-                ctxx.stroke();
-                ctxx.fill_rect(
-                    (x * scale) as f64,
-                    (y * scale) as f64,
-                    (scale - minus) as f64,
-                    (scale - minus) as f64,
-                );
-            }
-        }
-
-        // --------------------------------------------------------------
-        // let dim = self.field.dim;
-        // let mut buffer = vec![];
-        // for cell in self.field.data.iter() {
-        //     buffer.extend(cell.px.rgba);
-        // }
-
-        // // log!(format!("{:?}", buffer));
-        // let arr = Clamped(buffer.as_slice());
-        // let img = ImageData::new_with_u8_clamped_array_and_sh(arr, dim as u32, dim as u32).unwrap();
-
-        // ctxx.clear_rect(0.0, 0.0, canvas.width() as f64, canvas.height() as f64);
-        // canvas.set_width(dim as u32);
-        // canvas.set_height(dim as u32);
-        // let res = ctxx.put_image_data(&img, 1.0, 1.0);
-
-        // log!(format!("{:?}", res));
+        if let Some(sel) = &self.selection {
+            ctxx.begin_path();
+            for (i, p) in sel.points().into_iter().enumerate() {
+                let (x, y) = self.viewport.to_screen(p);
+                match i {
+                    0 => ctxx.move_to(x, y),
+                    _ => ctxx.line_to(x, y),
+                }
+            }
+            ctxx.close_path();
+            ctxx.set_stroke_style_str("white");
+            ctxx.stroke();
+        }
+        self.frame_ms = self.timer.elapsed_ms();
+    }
+
+    /// Repaints only the on-screen parts of the regions the field changed since the
+    /// last draw. Falls back to a full render while a selection outline is on screen
+    /// or the size changed. Heatmaps rescale with every step, they are redrawn once
+    /// per frame instead, see `Msg::Frame`.
+    fn render_dirty(&mut self) {
+        if self.settings.output.mode != RenderMode::Colour {
+            return;
+        }
+        let (w, h) = (self.field.width as u32, self.field.height as u32);
+        let resized = self.buffer.width() != w || self.buffer.height() != h;
+        if self.selection.is_some() || resized {
+            return self.render_canvas();
+        }
+
+        self.timer.start_time();
+        let (ctxx, bctx) = self.contexts();
+        let visible = self.viewport.visible(w as usize, h as usize);
+        for r in self.field.take_dirty() {
+            if let Some(r) = visible.and_then(|v| v.intersect(&r)) {
+                let data = heatmap::colour_region(&self.field, r, self.settings.output.blank);
+                self.blit(&ctxx, &bctx, r, &data);
+            }
+        }
+        self.frame_ms = self.timer.elapsed_ms();
     }
 }
+
+/// Compass name of a neighbour offset, north is up.
+fn direction(dx: i64, dy: i64) -> &'static str {
+    match (dx, dy) {
+        (-1, -1) => "NW",
+        (0, -1) => "N",
+        (1, -1) => "NE",
+        (-1, 0) => "W",
+        (1, 0) => "E",
+        (-1, 1) => "SW",
+        (0, 1) => "S",
+        _ => "SE",
+    }
+}
+
+/// Settings from the page url's fragment, as put there by `Canvas::permalink`.
+fn linked_settings() -> Option<Result<Settings, String>> {
+    let hash = window().location().hash().ok()?;
+    let fragment = hash.strip_prefix('#').filter(|f| !f.is_empty())?;
+    let json = match js_sys::decode_uri_component(fragment) {
+        Ok(json) => String::from(json),
+        Err(_) => return Some(Err("malformed link".to_string())),
+    };
+    Some(Settings::parse(&json, Format::Json).map_err(|e| format!("link: {}", e)))
+}
+
+/// `ms` as seconds, or minutes and seconds for longer spans.
+fn duration(ms: f64) -> String {
+    let secs = ms / 1000.0;
+    match secs < 60.0 {
+        true => format!("{:.1} s", secs),
+        false => format!("{}m {:02}s", (secs / 60.0) as u64, secs as u64 % 60),
+    }
+}
+
+/// Parses a `#rrggbb` colour as produced by `<input type="color">`.
+fn parse_hex(v: &str) -> Option<Rgba> {
+    let v = v.strip_prefix('#')?;
+    let c = |i: usize| u8::from_str_radix(v.get(i..i + 2)?, 16).ok();
+    Some([c(0)?, c(2)?, c(4)?, 255])
+}
@@ -0,0 +1,123 @@
+use serde::{Deserialize, Serialize};
+
+use crate::frontier::BitSet;
+
+/// Side length of a dirty tile in cells.
+pub const TILE: usize = 32;
+
+/// A rectangle of cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+}
+
+impl Rect {
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        (self.x..self.x + self.w).contains(&x) && (self.y..self.y + self.h).contains(&y)
+    }
+
+    pub fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let x0 = self.x.max(other.x);
+        let y0 = self.y.max(other.y);
+        let x1 = (self.x + self.w).min(other.x + other.w);
+        let y1 = (self.y + self.h).min(other.y + other.h);
+        if x0 >= x1 || y0 >= y1 {
+            return None;
+        }
+        Some(Rect {
+            x: x0,
+            y: y0,
+            w: x1 - x0,
+            h: y1 - y0,
+        })
+    }
+
+    /// This rect out of a row-major RGBA buffer `width` cells wide.
+    pub fn crop_rgba(&self, bytes: &[u8], width: usize) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.w * self.h * 4);
+        for y in self.y..self.y + self.h {
+            let start = (y * width + self.x) * 4;
+            out.extend_from_slice(&bytes[start..start + self.w * 4]);
+        }
+        out
+    }
+}
+
+/// Tracks which `TILE`-sized tiles of the field changed since the last draw.
+#[derive(Debug, Clone, Default)]
+pub struct DirtyTiles {
+    width: usize,
+    height: usize,
+    cols: usize,
+    rows: usize,
+    tiles: BitSet,
+    any: bool,
+}
+
+impl DirtyTiles {
+    /// Starts out fully dirty, nothing has been drawn yet.
+    pub fn new(width: usize, height: usize) -> Self {
+        let (cols, rows) = (width.div_ceil(TILE), height.div_ceil(TILE));
+        let mut dirty = Self {
+            width,
+            height,
+            cols,
+            rows,
+            tiles: BitSet::new(cols * rows),
+            any: false,
+        };
+        dirty.mark_all();
+        dirty
+    }
+
+    pub fn mark(&mut self, x: usize, y: usize) {
+        self.tiles.set((y / TILE) * self.cols + x / TILE, true);
+        self.any = true;
+    }
+
+    pub fn mark_all(&mut self) {
+        (0..self.cols * self.rows).for_each(|i| self.tiles.set(i, true));
+        self.any = self.cols * self.rows > 0;
+    }
+
+    pub fn is_clean(&self) -> bool {
+        !self.any
+    }
+
+    /// Dirty regions as rects in cell coordinates, clipped to the field. Neighbouring
+    /// dirty tiles in a row are merged into one rect. Marks everything clean.
+    pub fn take_rects(&mut self) -> Vec<Rect> {
+        let mut rects = vec![];
+        if !self.any {
+            return rects;
+        }
+
+        for row in 0..self.rows {
+            let mut col = 0;
+            while col < self.cols {
+                if !self.tiles.get(row * self.cols + col) {
+                    col += 1;
+                    continue;
+                }
+                let start = col;
+                while col < self.cols && self.tiles.get(row * self.cols + col) {
+                    col += 1;
+                }
+                let (x, y) = (start * TILE, row * TILE);
+                rects.push(Rect {
+                    x,
+                    y,
+                    w: (col * TILE).min(self.width) - x,
+                    h: ((row + 1) * TILE).min(self.height) - y,
+                });
+            }
+        }
+
+        self.tiles.clear();
+        self.any = false;
+        rects
+    }
+}
@@ -0,0 +1,135 @@
+use crate::protocol::Pack;
+
+/// Fixed-size bitset, one bit per cell.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BitSet {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl BitSet {
+    pub fn new(len: usize) -> Self {
+        Self {
+            words: vec![0; len.div_ceil(64)],
+            len,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, i: usize) -> bool {
+        self.words[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    pub fn set(&mut self, i: usize, v: bool) {
+        let bit = 1 << (i % 64);
+        match v {
+            true => self.words[i / 64] |= bit,
+            false => self.words[i / 64] &= !bit,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.words.iter_mut().for_each(|w| *w = 0);
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+}
+
+impl Pack for BitSet {
+    fn pack(&self, out: &mut Vec<u8>) {
+        (self.len as u32).pack(out);
+        self.words.pack(out);
+    }
+
+    fn unpack(buf: &mut &[u8]) -> Option<Self> {
+        let len = u32::unpack(buf)? as usize;
+        let words = Vec::<u64>::unpack(buf)?;
+        (words.len() == len.div_ceil(64)).then_some(Self { words, len })
+    }
+}
+
+/// Collapsed cells that may still have uncollapsed neighbours, kept in insertion
+/// order with a flag per cell so membership checks and pushes are O(1).
+#[derive(Debug, Clone, Default)]
+pub struct Frontier {
+    cells: Vec<u32>,
+    flags: BitSet,
+}
+
+impl Frontier {
+    pub fn new(len: usize) -> Self {
+        Self {
+            cells: vec![],
+            flags: BitSet::new(len),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    pub fn contains(&self, idx: usize) -> bool {
+        self.flags.get(idx)
+    }
+
+    /// Adds `idx` unless it is already queued.
+    pub fn push(&mut self, idx: usize) -> bool {
+        if self.flags.get(idx) {
+            return false;
+        }
+        self.flags.set(idx, true);
+        self.cells.push(idx as u32);
+        true
+    }
+
+    /// Removes and returns the queued cells, in the order they were pushed.
+    pub fn take(&mut self) -> Vec<u32> {
+        let cells = std::mem::take(&mut self.cells);
+        cells
+            .iter()
+            .for_each(|&i| self.flags.set(i as usize, false));
+        cells
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(usize) -> bool) {
+        let flags = &mut self.flags;
+        self.cells.retain(|&i| {
+            let keep = f(i as usize);
+            if !keep {
+                flags.set(i as usize, false);
+            }
+            keep
+        });
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.cells.iter().map(|&i| i as usize)
+    }
+
+    pub fn first(&self) -> Option<usize> {
+        self.cells.first().map(|&i| i as usize)
+    }
+
+    /// Empties the frontier and resizes it for a field of `len` cells.
+    pub fn reset(&mut self, len: usize) {
+        self.cells.clear();
+        if self.flags.len() == len {
+            self.flags.clear();
+        } else {
+            self.flags = BitSet::new(len);
+        }
+    }
+}
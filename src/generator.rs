@@ -0,0 +1,396 @@
+use yew_agent::{HandlerId, Private, WorkerLink};
+
+use crate::history::History;
+use crate::parallel::{self, TileResult};
+use crate::protocol::{
+    check_size, Command, Packed, ProtocolError, Request, Response, Status, Update, PROTOCOL_VERSION,
+};
+use crate::run::{Finished, GrowthMode, RunController};
+use crate::topology::TopologyKind;
+use crate::types::JSTimer;
+use crate::wfc_field::{CellState, ChangeSet, WFCField};
+
+const HISTORY_BYTES: usize = 64 << 20;
+/// How long a batch of epochs may run before its changes are sent, until the UI
+/// configures its own frame budget.
+pub const BATCH_MS: f64 = 15.0;
+
+/// Owns the field and its history and runs the growth off the main thread, so the
+/// page stays responsive however large the field gets.
+pub struct Generator {
+    link: WorkerLink<Self>,
+    client: Option<HandlerId>,
+    field: WFCField,
+    history: History,
+    mode: GrowthMode,
+    /// Time per batch, see `Command::Configure`.
+    budget_ms: f64,
+    running: bool,
+    /// Epochs left to run for a `Command::Step`.
+    steps: usize,
+    /// Times the current run. Cancelling ends it, the next start begins a new one.
+    run: RunController,
+    /// Updates sent but not acknowledged yet, no new batch starts before they are.
+    unacked: usize,
+    /// Set by edits that leave cells to regrow. Until the field is complete again
+    /// only completion stops growth, not epoch or time limits an earlier run may
+    /// already have reached.
+    regrow: bool,
+    /// Id of the parallel epochs out at the worker pool, if any.
+    jobs_out: Option<u64>,
+    next_job: u64,
+}
+
+impl Generator {
+    fn status(&self) -> Status {
+        Status {
+            epoch: self.field.epoch_idx,
+            seed: self.field.seed,
+            running: self.running,
+            steps: self.steps,
+            remaining: self.field.remaining(),
+            can_undo: self.history.can_undo(),
+            can_redo: self.history.can_redo(),
+            first_epoch: self.history.first_epoch(),
+            history_bytes: self.history.bytes(),
+            progress: self.run.progress(&self.field),
+            cancelled: self.run.is_cancelled(),
+        }
+    }
+
+    fn send(&mut self, update: Update) {
+        if let Some(id) = self.client {
+            if matches!(update, Update::Snapshot { .. } | Update::Changes { .. }) {
+                self.unacked += 1;
+            }
+            self.link.respond(id, Response::from(update));
+        }
+    }
+
+    fn send_changes(&mut self, changes: Vec<(u32, CellState)>) {
+        let status = self.status();
+        self.send(Update::Changes {
+            changes: Packed(changes),
+            status,
+        });
+    }
+
+    fn send_progress(&mut self) {
+        let status = self.status();
+        self.send(Update::Progress(status));
+    }
+
+    fn send_snapshot(&mut self) {
+        let update = Update::Snapshot {
+            width: self.field.width,
+            height: self.field.height,
+            topology: self.field.topology().kind(),
+            cells: Packed(self.field.cells.clone()),
+            status: self.status(),
+        };
+        self.send(update);
+    }
+
+    /// Drops parallel epochs in flight, their tiles no longer match the field.
+    fn cancel_jobs(&mut self) {
+        self.jobs_out = None;
+    }
+
+    /// Swaps in a new field with `topology` and the current stop conditions and
+    /// variation, and starts over.
+    fn replace_field(&mut self, mut field: WFCField, topology: TopologyKind) {
+        self.cancel_jobs();
+        self.stop();
+        self.run.reset();
+        self.regrow = false;
+        if topology != field.topology().kind() {
+            field.set_topology(topology.build(field.width, field.height));
+        }
+        field.set_stop_conditions(self.field.stop_conditions().to_vec());
+        field.set_variation(*self.field.variation());
+        self.field = field;
+        self.history.clear();
+        self.send_snapshot();
+    }
+
+    /// The stop condition growth has met, if any, see `regrow`.
+    fn finished(&self) -> Option<Finished> {
+        match self.regrow {
+            true => self.field.completed(),
+            false => self.field.finished(self.run.elapsed_ms()),
+        }
+    }
+
+    /// Stops and tells the UI if a stop condition is met, true if so.
+    fn check_finished(&mut self) -> bool {
+        let Some(finished) = self.finished() else {
+            return false;
+        };
+        self.regrow = false;
+        self.stop();
+        let status = self.status();
+        self.send(Update::Finished { finished, status });
+        true
+    }
+
+    /// Starts running, or stepping through `steps` epochs.
+    fn start(&mut self, running: bool, steps: usize) {
+        self.running = running;
+        self.steps = steps;
+        self.run.resume(self.field.len() - self.field.remaining());
+        self.grow();
+    }
+
+    /// Stops growth, whether running or stepping.
+    fn stop(&mut self) {
+        self.running = false;
+        self.steps = 0;
+        self.run.pause();
+    }
+
+    /// Counts off an epoch of a `Command::Step`, true once the last one is done.
+    fn step_done(&mut self) -> bool {
+        if self.running || self.steps == 0 {
+            return false;
+        }
+        self.steps -= 1;
+        if self.steps == 0 {
+            self.run.pause();
+        }
+        self.steps == 0
+    }
+
+    /// Records a step and sends what it changed.
+    fn finish_step(&mut self, set: ChangeSet) {
+        let changes = set.writes().collect();
+        self.history.record(set);
+        self.send_changes(changes);
+    }
+
+    /// Runs epochs for up to `budget_ms`, or hands the next parallel epochs to the
+    /// worker pool, unless stopped or the UI is still busy with the last update.
+    fn grow(&mut self) {
+        let idle = !self.running && self.steps == 0;
+        if idle || self.unacked > 0 || self.jobs_out.is_some() || self.check_finished() {
+            return;
+        }
+        if self.mode == GrowthMode::Parallel {
+            return self.send_jobs();
+        }
+
+        let mut timer = JSTimer::new();
+        timer.start_time();
+        let mut changes = vec![];
+        loop {
+            let set = self.mode.epoch(&mut self.field);
+            changes.extend(set.writes());
+            self.history.record(set);
+            let done = self.step_done() || self.finished().is_some();
+            if done || timer.elapsed_ms() >= self.budget_ms {
+                break;
+            }
+        }
+        self.send_changes(changes);
+    }
+
+    /// Sends jobs for `JOB_EPOCHS` epochs, fewer when stepping or when a batch
+    /// is held to a single epoch.
+    fn send_jobs(&mut self) {
+        let mut epochs = match self.budget_ms > 0.0 {
+            true => parallel::JOB_EPOCHS,
+            false => 1,
+        };
+        if !self.running {
+            epochs = epochs.min(self.steps);
+        }
+        let mut jobs = parallel::jobs(&self.field, epochs);
+        if jobs.is_empty() {
+            let set = self.field.apply_tiles(&[], 0);
+            self.step_done();
+            return self.finish_step(set);
+        }
+
+        self.next_job += 1;
+        jobs.iter_mut().for_each(|j| j.id = self.next_job);
+        self.jobs_out = Some(self.next_job);
+        self.send(Update::Jobs(jobs));
+    }
+
+    /// Applies the epochs of the tiles that came back one by one, like `grow` runs
+    /// them, and drops the rest once growth stops or was paused meanwhile.
+    fn apply_tiles(&mut self, results: Vec<TileResult>) {
+        let epochs = results.iter().map(|r| r.cells.0.len()).max().unwrap_or(0);
+        let mut changes = vec![];
+        for epoch in 0..epochs {
+            let set = self.field.apply_tiles(&results, epoch);
+            changes.extend(set.writes());
+            self.history.record(set);
+            let paused = !self.running && self.steps == 0;
+            if self.step_done() || paused || self.finished().is_some() {
+                break;
+            }
+        }
+        self.send_changes(changes);
+    }
+
+    fn run(&mut self, cmd: Command) -> Result<(), ProtocolError> {
+        match cmd {
+            Command::Reset {
+                width,
+                height,
+                seed,
+                topology,
+            } => {
+                check_size(width, height)?;
+                self.replace_field(WFCField::new(width, height, seed), topology);
+            }
+            Command::Import {
+                width,
+                height,
+                rgba: Packed(rgba),
+                key,
+                seed,
+                topology,
+            } => {
+                check_size(width, height)?;
+                if rgba.len() != width * height * 4 {
+                    return Err(ProtocolError::BufferSize {
+                        expected: width * height * 4,
+                        got: rgba.len(),
+                    });
+                }
+                let field = WFCField::from_image(width, height, &rgba, key, seed);
+                self.replace_field(field, topology);
+            }
+            Command::Configure {
+                mode,
+                budget_ms,
+                variation,
+            } => {
+                self.mode = mode;
+                self.budget_ms = budget_ms.max(0.0);
+                self.field.set_variation(variation);
+                self.send_progress();
+            }
+            Command::StopWhen(conditions) => {
+                self.field.set_stop_conditions(conditions);
+                self.send_progress();
+            }
+            Command::Seed(seed) => {
+                self.field.reseed(seed);
+                self.send_progress();
+            }
+            Command::Start => self.start(true, 0),
+            Command::Pause => {
+                self.stop();
+                self.send_progress();
+            }
+            Command::Step(epochs) => self.start(false, epochs),
+            Command::Cancel => {
+                self.cancel_jobs();
+                self.stop();
+                self.run.cancel();
+                self.send_progress();
+            }
+            Command::Snapshot => self.send_snapshot(),
+            Command::Extend([0, 0, 0, 0]) => {}
+            Command::Extend([top, right, bottom, left]) => {
+                let width = self.field.width.saturating_add(left).saturating_add(right);
+                let height = self.field.height.saturating_add(top).saturating_add(bottom);
+                check_size(width, height)?;
+                self.cancel_jobs();
+                self.field.extend(top, right, bottom, left);
+                self.regrow = true;
+                self.history.clear();
+                self.send_snapshot();
+            }
+            Command::Reroll { selection, seed } => {
+                self.cancel_jobs();
+                let cells = selection.cells(self.field.width, self.field.height);
+                let set = self.field.reroll(&cells, seed);
+                self.regrow = true;
+                self.running = true;
+                self.steps = 0;
+                self.run.resume(self.field.len() - self.field.remaining());
+                self.finish_step(set);
+            }
+            Command::Undo => {
+                self.cancel_jobs();
+                self.stop();
+                let changes = self.history.undo(&mut self.field);
+                self.send_changes(changes);
+            }
+            Command::Redo => {
+                self.cancel_jobs();
+                self.stop();
+                let changes = self.history.redo(&mut self.field);
+                self.send_changes(changes);
+            }
+            Command::JumpTo(epoch) => {
+                self.cancel_jobs();
+                self.stop();
+                let changes = self.history.jump_to(&mut self.field, epoch);
+                self.send_changes(changes);
+            }
+            Command::Ack => {
+                self.unacked = self.unacked.saturating_sub(1);
+                self.grow();
+            }
+            Command::Tiles(results) => {
+                let current = results.first().map(|r| r.id) == self.jobs_out;
+                if current {
+                    self.jobs_out = None;
+                    self.apply_tiles(results);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl yew_agent::Worker for Generator {
+    type Input = Request;
+    type Message = ();
+    type Output = Response;
+    type Reach = Private<Self>;
+
+    fn create(link: WorkerLink<Self>) -> Self {
+        Self {
+            link,
+            client: None,
+            // replaced by the first `Reset` or `Import`
+            field: WFCField::new(1, 1, 0),
+            history: History::new(HISTORY_BYTES),
+            mode: GrowthMode::default(),
+            budget_ms: BATCH_MS,
+            running: false,
+            steps: 0,
+            run: RunController::new(),
+            unacked: 0,
+            regrow: false,
+            jobs_out: None,
+            next_job: 0,
+        }
+    }
+
+    fn update(&mut self, _msg: Self::Message) {
+        // no messaging
+    }
+
+    fn handle_input(&mut self, req: Self::Input, id: HandlerId) {
+        self.client = Some(id);
+        if req.version != PROTOCOL_VERSION {
+            return self.send(Update::Error(ProtocolError::Version {
+                expected: PROTOCOL_VERSION,
+                got: req.version,
+            }));
+        }
+        if let Err(e) = self.run(req.command) {
+            self.send(Update::Error(e));
+        }
+    }
+
+    fn name_of_resource() -> &'static str {
+        "generator.js"
+    }
+}
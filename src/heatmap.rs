@@ -0,0 +1,255 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::dirty::Rect;
+use crate::topology::MAX_NEIGHBOURS;
+use crate::types::Rgba;
+use crate::wfc_field::{Pixel, WFCField};
+
+/// Cells without a value in the current mode, e.g. uncollapsed ones.
+const NO_VALUE: Rgba = [0, 0, 0, 255];
+
+/// What the canvas shows for each cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RenderMode {
+    /// The generated colours.
+    #[default]
+    Colour,
+    /// Epoch each cell collapsed in.
+    Epoch,
+    /// Order in which cells collapsed.
+    Order,
+    /// The generated colours in grey, with the frontier (collapsed cells that still
+    /// border uncollapsed ones) on top.
+    Frontier,
+    /// Steps to the nearest seed or imported cell.
+    SeedDistance,
+    /// Collapsed neighbours a cell's colour was averaged from.
+    Neighbours,
+}
+
+impl RenderMode {
+    pub const ALL: [RenderMode; 6] = [
+        RenderMode::Colour,
+        RenderMode::Epoch,
+        RenderMode::Order,
+        RenderMode::Frontier,
+        RenderMode::SeedDistance,
+        RenderMode::Neighbours,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RenderMode::Colour => "Colour",
+            RenderMode::Epoch => "Collapse epoch",
+            RenderMode::Order => "Collapse order",
+            RenderMode::Frontier => "Frontier",
+            RenderMode::SeedDistance => "Distance from seed",
+            RenderMode::Neighbours => "Neighbours at collapse",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|m| m.name() == name)
+    }
+}
+
+/// How uncollapsed cells are drawn in `RenderMode::Colour`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Blank {
+    /// As stored, black.
+    Black,
+    /// A transparency checkerboard.
+    Checkerboard,
+    /// The colour the cell is likely to collapse to, over the checkerboard. Cells
+    /// without collapsed neighbours show just the checkerboard.
+    #[default]
+    Expected,
+}
+
+impl Blank {
+    pub const ALL: [Blank; 3] = [Blank::Black, Blank::Checkerboard, Blank::Expected];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Blank::Black => "Black",
+            Blank::Checkerboard => "Checkerboard",
+            Blank::Expected => "Expected colour",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|b| b.name() == name)
+    }
+}
+
+fn checker(x: usize, y: usize) -> Rgba {
+    match (x + y) % 2 {
+        0 => [204, 204, 204, 255],
+        _ => [255, 255, 255, 255],
+    }
+}
+
+/// RGBA bytes of `rect` in `RenderMode::Colour`, uncollapsed cells drawn as `blank`.
+pub fn colour_region(field: &WFCField, rect: Rect, blank: Blank) -> Vec<u8> {
+    let mut data = field.rgba_region(rect);
+    if blank == Blank::Black {
+        return data;
+    }
+
+    for y in rect.y..rect.y + rect.h {
+        for x in rect.x..rect.x + rect.w {
+            let idx = field.idx((x, y));
+            if field.cells.is_collapsed(idx) {
+                continue;
+            }
+            let bg = checker(x, y);
+            let px = match blank {
+                Blank::Expected => field.expected(idx).map_or(bg, |c| {
+                    // two thirds colour, so previews stay apart from collapsed cells
+                    [0, 1, 2, 3].map(|i| ((c[i] as u16 * 2 + bg[i] as u16) / 3) as u8)
+                }),
+                _ => bg,
+            };
+            let o = ((y - rect.y) * rect.w + x - rect.x) * 4;
+            data[o..o + 4].copy_from_slice(&px);
+        }
+    }
+    data
+}
+
+/// Blue for 0 through to red for 1.
+fn ramp(t: f64) -> Rgba {
+    Pixel::hsl2rgb(240.0 * (1.0 - t.clamp(0.0, 1.0)), 1.0, 0.5)
+}
+
+/// Breadth-first steps from every seed (collapsed without sources) through the
+/// topology, `None` for cells no seed reaches.
+fn seed_distance(field: &WFCField) -> Vec<Option<u32>> {
+    let mut dist = vec![None; field.len()];
+    let mut queue = VecDeque::new();
+    for (idx, d) in dist.iter_mut().enumerate() {
+        if field.cells.is_collapsed(idx) && field.cells.origin(idx).sources == 0 {
+            *d = Some(0);
+            queue.push_back(idx);
+        }
+    }
+
+    while let Some(idx) = queue.pop_front() {
+        let next = dist[idx].map(|d| d + 1);
+        for n in field.topology().neighbours(idx).iter() {
+            if dist[n].is_none() && field.cells.is_collapsed(n) {
+                dist[n] = next;
+                queue.push_back(n);
+            }
+        }
+    }
+    dist
+}
+
+/// What a mode needs from the whole field before any part of it can be drawn: the
+/// range values are scaled to and, for `SeedDistance`, the distances. Getting it
+/// takes a pass over every cell, so it's kept for as long as the field doesn't
+/// change and regions are drawn from it.
+#[derive(Debug, Clone)]
+pub struct Heatmap {
+    mode: RenderMode,
+    min: u32,
+    max: u32,
+    distance: Vec<Option<u32>>,
+}
+
+impl Heatmap {
+    pub fn new(field: &WFCField, mode: RenderMode) -> Self {
+        let mut heatmap = Self {
+            mode,
+            min: 0,
+            max: 0,
+            distance: vec![],
+        };
+        if mode == RenderMode::SeedDistance {
+            heatmap.distance = seed_distance(field);
+        }
+        if matches!(
+            mode,
+            RenderMode::Epoch | RenderMode::Order | RenderMode::SeedDistance
+        ) {
+            let values = (0..field.len()).filter_map(|i| heatmap.value(field, i));
+            let (min, max) = values.fold((u32::MAX, 0), |(lo, hi), v| (lo.min(v), hi.max(v)));
+            heatmap.min = min.min(max);
+            heatmap.max = max;
+        }
+        heatmap
+    }
+
+    pub fn mode(&self) -> RenderMode {
+        self.mode
+    }
+
+    /// Value of cell `idx` in the heatmap modes.
+    fn value(&self, field: &WFCField, idx: usize) -> Option<u32> {
+        let origin = field.cells.origin(idx);
+        let epoch = origin.epoch.filter(|_| field.cells.is_collapsed(idx));
+        match self.mode {
+            RenderMode::Epoch => epoch,
+            RenderMode::Order => epoch.map(|_| origin.order),
+            RenderMode::SeedDistance => self.distance[idx],
+            _ => None,
+        }
+    }
+
+    fn pixel(&self, field: &WFCField, idx: usize) -> Rgba {
+        let cells = &field.cells;
+        match self.mode {
+            RenderMode::Colour => cells.rgba(idx),
+            RenderMode::Epoch | RenderMode::Order | RenderMode::SeedDistance => {
+                let span = (self.max - self.min).max(1) as f64;
+                self.value(field, idx)
+                    .map_or(NO_VALUE, |v| ramp((v - self.min) as f64 / span))
+            }
+            RenderMode::Frontier => {
+                if cells.is_collapsed(idx) && field.blank_neighbours(idx) > 0 {
+                    return [255, 0, 255, 255];
+                }
+                let [r, g, b, a] = cells.rgba(idx);
+                let grey = ((r as u32 + g as u32 + b as u32) / 6) as u8;
+                [grey, grey, grey, a]
+            }
+            RenderMode::Neighbours => {
+                let origin = cells.origin(idx);
+                match cells.is_collapsed(idx) && origin.sources != 0 {
+                    true => {
+                        let n = origin.sources.count_ones() - 1;
+                        ramp(n as f64 / (MAX_NEIGHBOURS - 1) as f64)
+                    }
+                    false => NO_VALUE,
+                }
+            }
+        }
+    }
+
+    /// RGBA bytes of `rect`, row-major, for the field this was made from.
+    pub fn region(&self, field: &WFCField, rect: Rect) -> Vec<u8> {
+        let mut data = Vec::with_capacity(rect.w * rect.h * 4);
+        for y in rect.y..rect.y + rect.h {
+            for x in rect.x..rect.x + rect.w {
+                data.extend_from_slice(&self.pixel(field, field.idx((x, y))));
+            }
+        }
+        data
+    }
+}
+
+/// The whole field as RGBA bytes in `mode`, row-major like `CellStore::rgba_bytes`.
+pub fn render(field: &WFCField, mode: RenderMode) -> Vec<u8> {
+    let rect = Rect {
+        x: 0,
+        y: 0,
+        w: field.width,
+        h: field.height,
+    };
+    Heatmap::new(field, mode).region(field, rect)
+}
@@ -0,0 +1,359 @@
+use std::collections::VecDeque;
+use std::mem::size_of;
+
+use crate::types::Rgba;
+use crate::wfc_field::{CellChange, CellState, ChangeSet, GrowthState, Origin, WFCField};
+
+/// A cell collapsed by growth. It was blank before, and its epoch and order follow
+/// from the step, so this is all there is to keep of it.
+#[derive(Debug, Clone, Copy)]
+struct Grown {
+    idx: u32,
+    rgba: Rgba,
+    hsl: [f32; 3],
+    sources: u8,
+}
+
+#[derive(Debug, Clone)]
+enum Cells {
+    /// Growth steps, by far the most common.
+    Grown(Vec<Grown>),
+    /// Edits and anything else that doesn't fit `Grown`.
+    Changed(Vec<CellChange>),
+}
+
+/// A recorded step.
+#[derive(Debug, Clone)]
+struct Delta {
+    epoch: usize,
+    epochs: usize,
+    start: GrowthState,
+    end: GrowthState,
+    cells: Cells,
+}
+
+impl Delta {
+    fn new(set: ChangeSet) -> Self {
+        let ChangeSet {
+            epoch,
+            epochs,
+            changes,
+            start,
+            end,
+            ..
+        } = set;
+        let blank = CellState::blank();
+        let grown = |i: usize, c: &CellChange| {
+            let origin = Origin {
+                epoch: Some(epoch as u32),
+                order: start.collapses + i as u32 + 1,
+                sources: c.after.origin.sources,
+            };
+            (c.before == blank && c.after.collapsed && c.after.origin == origin).then_some(Grown {
+                idx: c.idx,
+                rgba: c.after.rgba,
+                hsl: c.after.hsl,
+                sources: origin.sources,
+            })
+        };
+        let compact: Option<Vec<_>> = match epochs {
+            0 => None,
+            _ => changes
+                .iter()
+                .enumerate()
+                .map(|(i, c)| grown(i, c))
+                .collect(),
+        };
+        let cells = match compact {
+            Some(grown) => Cells::Grown(grown),
+            None => Cells::Changed(changes),
+        };
+        Self {
+            epoch,
+            epochs,
+            start,
+            end,
+            cells,
+        }
+    }
+
+    fn changes(&self) -> Vec<CellChange> {
+        let grown = match &self.cells {
+            Cells::Changed(changes) => return changes.clone(),
+            Cells::Grown(grown) => grown,
+        };
+        let blank = CellState::blank();
+        grown
+            .iter()
+            .enumerate()
+            .map(|(i, g)| CellChange {
+                idx: g.idx,
+                before: blank,
+                after: CellState {
+                    rgba: g.rgba,
+                    hsl: g.hsl,
+                    collapsed: true,
+                    origin: Origin {
+                        epoch: Some(self.epoch as u32),
+                        order: self.start.collapses + i as u32 + 1,
+                        sources: g.sources,
+                    },
+                },
+            })
+            .collect()
+    }
+
+    fn bytes(&self) -> usize {
+        size_of::<Delta>()
+            + match &self.cells {
+                Cells::Grown(grown) => grown.capacity() * size_of::<Grown>(),
+                Cells::Changed(changes) => changes.capacity() * size_of::<CellChange>(),
+            }
+    }
+}
+
+/// Undo/redo stack of per-step change sets. Once the recorded deltas exceed
+/// `max_bytes` the oldest ones are dropped, so they can no longer be undone.
+///
+/// Moving through the history returns the writes it made, in order, so a copy of
+/// the field can follow along with `apply_writes`. The field's
+/// `GrowthState` is restored too, so growing on takes the same path as before.
+pub struct History {
+    undo: VecDeque<Delta>,
+    redo: Vec<Delta>,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl History {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: vec![],
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    pub fn record(&mut self, mut set: ChangeSet) {
+        if set.is_empty() {
+            return;
+        }
+
+        set.changes.shrink_to_fit();
+        let delta = Delta::new(set);
+        self.bytes += delta.bytes();
+        self.undo.push_back(delta);
+        self.redo.iter().for_each(|d| self.bytes -= d.bytes());
+        self.redo.clear();
+
+        while self.bytes > self.max_bytes && self.undo.len() > 1 {
+            let old = self.undo.pop_front().unwrap();
+            self.bytes -= old.bytes();
+        }
+    }
+
+    pub fn undo(&mut self, field: &mut WFCField) -> Vec<(u32, CellState)> {
+        let mut applied = vec![];
+        let state = self.step_back(field, &mut applied);
+        finish(field, state);
+        applied
+    }
+
+    pub fn redo(&mut self, field: &mut WFCField) -> Vec<(u32, CellState)> {
+        let mut applied = vec![];
+        let state = self.step_forward(field, &mut applied);
+        finish(field, state);
+        applied
+    }
+
+    /// Undoes or redoes steps until the field is at `epoch`, or as close as the
+    /// recorded history allows.
+    pub fn jump_to(&mut self, field: &mut WFCField, epoch: usize) -> Vec<(u32, CellState)> {
+        let mut applied = vec![];
+        let mut state = None;
+        while field.epoch_idx > epoch {
+            match self.step_back(field, &mut applied) {
+                Some(s) => state = Some(s),
+                None => break,
+            }
+        }
+        while field.epoch_idx < epoch
+            && self
+                .redo
+                .last()
+                .is_some_and(|d| d.epoch + d.epochs <= epoch)
+        {
+            match self.step_forward(field, &mut applied) {
+                Some(s) => state = Some(s),
+                None => break,
+            }
+        }
+        finish(field, state);
+        applied
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.bytes = 0;
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Oldest epoch that can still be reached by undoing.
+    pub fn first_epoch(&self) -> Option<usize> {
+        self.undo.front().map(|d| d.epoch)
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Undoes the last step, returning the growth state to go on from.
+    fn step_back(
+        &mut self,
+        field: &mut WFCField,
+        applied: &mut Vec<(u32, CellState)>,
+    ) -> Option<GrowthState> {
+        let delta = self.undo.pop_back()?;
+        let changes = delta.changes();
+        field.apply_changes(&changes, true);
+        applied.extend(changes.iter().rev().map(|c| (c.idx, c.before)));
+        field.epoch_idx = delta.epoch;
+        let state = delta.start;
+        self.redo.push(delta);
+        Some(state)
+    }
+
+    /// Redoes the last undone step, returning the growth state to go on from.
+    fn step_forward(
+        &mut self,
+        field: &mut WFCField,
+        applied: &mut Vec<(u32, CellState)>,
+    ) -> Option<GrowthState> {
+        let delta = self.redo.pop()?;
+        let changes = delta.changes();
+        field.apply_changes(&changes, false);
+        applied.extend(changes.iter().map(|c| (c.idx, c.after)));
+        field.epoch_idx = delta.epoch + delta.epochs;
+        let state = delta.end;
+        self.undo.push_back(delta);
+        Some(state)
+    }
+}
+
+/// Sets up the field to grow on from `state` after moving through the history.
+fn finish(field: &mut WFCField, state: Option<GrowthState>) {
+    if let Some(state) = state {
+        field.rebuild_frontier();
+        field.restore(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::{GrowthMode, StopCondition};
+    use crate::settings::Settings;
+
+    #[test]
+    fn undo_redo_restores_cells_exactly() {
+        let mut field = WFCField::new(60, 60, 7);
+        let mut other = WFCField::new(60, 60, 7);
+        let mut history = History::new(usize::MAX);
+        for _ in 0..10 {
+            history.record(field.epoch3());
+            other.epoch3();
+        }
+
+        let before = field.cells.clone();
+        history.undo(&mut field);
+        assert!(field.cells != before);
+        history.redo(&mut field);
+        assert!(field.cells == before);
+
+        // growth goes on as if nothing happened
+        for _ in 0..40 {
+            field.epoch3();
+            other.epoch3();
+        }
+        assert!(field.cells == other.cells);
+    }
+
+    #[test]
+    fn growth_after_undo_matches_a_fresh_run() {
+        for mode in GrowthMode::ALL {
+            let mut fresh = WFCField::new(60, 60, 7);
+            let mut field = WFCField::new(60, 60, 7);
+            let mut history = History::new(usize::MAX);
+            for _ in 0..10 {
+                mode.epoch(&mut fresh);
+                history.record(mode.epoch(&mut field));
+            }
+
+            history.undo(&mut field);
+            history.undo(&mut field);
+            for _ in 0..2 {
+                history.record(mode.epoch(&mut field));
+            }
+            assert!(field.cells == fresh.cells, "{} after undo", mode.name());
+
+            history.jump_to(&mut field, 5);
+            assert_eq!(field.epoch_idx, 5);
+            for _ in 0..5 {
+                mode.epoch(&mut field);
+            }
+            assert!(field.cells == fresh.cells, "{} after jump", mode.name());
+        }
+    }
+
+    #[test]
+    fn link_settings_grow_the_field_moved_through() {
+        let mut settings = Settings {
+            width: 50,
+            height: 40,
+            seed: 11,
+            growth: GrowthMode::Epoch2,
+            ..Settings::default()
+        };
+        let mut field = settings.build();
+        let mut history = History::new(usize::MAX);
+        for _ in 0..12 {
+            history.record(settings.growth.epoch(&mut field));
+        }
+        history.jump_to(&mut field, 4);
+        history.redo(&mut field);
+        for _ in 0..3 {
+            history.record(settings.growth.epoch(&mut field));
+        }
+
+        // what the link holds: the settings, stopping at the current epoch
+        settings.stop = vec![StopCondition::MaxEpochs(field.epoch_idx)];
+        let mut linked = settings.build();
+        while linked.finished(0.0).is_none() {
+            settings.growth.epoch(&mut linked);
+        }
+        assert!(linked.cells == field.cells);
+    }
+
+    #[test]
+    fn growth_is_recorded_compactly() {
+        let mut field = WFCField::new(100, 100, 3);
+        let mut history = History::new(usize::MAX);
+        while !field.is_complete() {
+            history.record(field.epoch3());
+        }
+        assert!(history.bytes() < field.len() * 2 * size_of::<Grown>());
+        assert_eq!(history.first_epoch(), Some(0));
+
+        history.jump_to(&mut field, 0);
+        assert_eq!(field.remaining(), field.len() - 1);
+    }
+}
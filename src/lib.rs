@@ -1,6 +1,19 @@
 mod canvas;
-mod wfc_field;
-mod types;
+pub mod dirty;
+pub mod frontier;
+pub mod generator;
+pub mod heatmap;
+pub mod history;
+pub mod parallel;
+pub mod protocol;
+pub mod run;
+pub mod selection;
+pub mod settings;
+pub mod storage;
+pub mod topology;
+pub mod types;
+pub mod viewport;
+pub mod wfc_field;
 pub mod worker;
 use crate::canvas::Canvas;
 
@@ -31,6 +44,3 @@ impl Component for App {
     }
 }
 
-fn main() {
-    yew::Renderer::<App>::new().render();
-}
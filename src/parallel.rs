@@ -0,0 +1,270 @@
+use std::collections::BTreeMap;
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+
+use crate::dirty::Rect;
+use crate::frontier::BitSet;
+use crate::protocol::Packed;
+use crate::topology::TopologyKind;
+use crate::types::{Hsl, Rand};
+use crate::wfc_field::{jitter, mean_value, ChangeSet, Variation, WFCField};
+
+/// Side length of the tiles parallel growth is split into.
+pub const JOB_TILE: usize = 64;
+
+/// Epochs a job runs when the generator isn't held to one epoch per batch.
+pub const JOB_EPOCHS: usize = 16;
+
+/// One tile of a few parallel epochs. A cell depends on nothing further away than
+/// one cell per epoch, so the tile grows together with a border of `epochs` cells
+/// around it, and only the tile's own cells are exact at the end. Of the cells
+/// already there only the ring bordering uncollapsed ones is shipped, the rest is
+/// never read.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TileJob {
+    /// Set by the sender, echoed back in the result to spot stale ones.
+    pub id: u64,
+    pub seed: u64,
+    /// Epoch of the field the job starts at.
+    pub epoch: u32,
+    pub epochs: usize,
+    pub topology: TopologyKind,
+    pub variation: Variation,
+    /// Width of the whole field.
+    pub width: usize,
+    /// The tile, in field coordinates.
+    pub tile: Rect,
+    /// The tile plus its border, cut off at the field's edges.
+    pub rect: Rect,
+    /// Collapsed cells in `rect`, row-major.
+    pub collapsed: Packed<BitSet>,
+    /// `rect` index and HSL of every collapsed cell in `rect` with an uncollapsed
+    /// neighbour.
+    pub ring: Packed<Vec<(u32, [f32; 3])>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TileResult {
+    pub id: u64,
+    /// Field index, colour and `Origin::sources` per cell of the tile collapsed,
+    /// one list per epoch of the job.
+    pub cells: Packed<Vec<Vec<(u32, Hsl, u8)>>>,
+}
+
+/// Generator for one cell in one epoch, so its colour doesn't depend on which
+/// worker computes it or in what order.
+fn cell_rng(seed: u64, epoch: u32, idx: u32) -> Rand {
+    let rng = Rand::new(seed);
+    rng.reseed(rng.next_u64() ^ ((epoch as u64) << 32 | idx as u64));
+    rng
+}
+
+/// Splits the next `epochs` parallel epochs of `field` into tile jobs. Like `epoch3`
+/// an epoch collapses every uncollapsed neighbour of the frontier, but all of them
+/// from the state before the epoch, so the jobs are independent of each other.
+pub fn jobs(field: &WFCField, epochs: usize) -> Vec<TileJob> {
+    let (width, height) = (field.width, field.height);
+    let reach = |v: usize, len: usize| {
+        v.saturating_sub(epochs) / JOB_TILE..=(v + epochs).min(len - 1) / JOB_TILE
+    };
+    // the ring cells every tile's border takes in
+    let mut tiles: BTreeMap<(usize, usize), Vec<usize>> = BTreeMap::new();
+    for cur in field.frontier().iter() {
+        if field.blank_neighbours(cur) == 0 {
+            continue;
+        }
+        let (x, y) = field.pos(cur);
+        for ty in reach(y, height) {
+            for tx in reach(x, width) {
+                tiles.entry((ty, tx)).or_default().push(cur);
+            }
+        }
+    }
+
+    let kind = field.topology().kind();
+    tiles
+        .into_iter()
+        .filter_map(|((ty, tx), ring)| {
+            let (x, y) = (tx * JOB_TILE, ty * JOB_TILE);
+            let tile = Rect {
+                x,
+                y,
+                w: JOB_TILE.min(width - x),
+                h: JOB_TILE.min(height - y),
+            };
+            let (x0, y0) = (x.saturating_sub(epochs), y.saturating_sub(epochs));
+            let x1 = (tile.x + tile.w + epochs).min(width);
+            let y1 = (tile.y + tile.h + epochs).min(height);
+            let rect = Rect {
+                x: x0,
+                y: y0,
+                w: x1 - x0,
+                h: y1 - y0,
+            };
+
+            let mut collapsed = BitSet::new(rect.w * rect.h);
+            let mut blank = false;
+            for ly in 0..rect.h {
+                for lx in 0..rect.w {
+                    let (fx, fy) = (rect.x + lx, rect.y + ly);
+                    let done = field.cells.is_collapsed(fy * width + fx);
+                    collapsed.set(ly * rect.w + lx, done);
+                    blank |= !done && tile.contains(fx, fy);
+                }
+            }
+            // nothing to grow in the tile itself
+            if !blank {
+                return None;
+            }
+
+            let ring = ring
+                .into_iter()
+                .map(|i| {
+                    let (fx, fy) = field.pos(i);
+                    let local = (fy - rect.y) * rect.w + fx - rect.x;
+                    (local as u32, field.cells.hsl(i).map(|v| v as f32))
+                })
+                .collect();
+            Some(TileJob {
+                id: 0,
+                seed: field.seed,
+                epoch: field.epoch_idx as u32,
+                epochs,
+                topology: kind,
+                variation: *field.variation(),
+                width,
+                tile,
+                rect,
+                collapsed: Packed(collapsed),
+                ring: Packed(ring),
+            })
+        })
+        .collect()
+}
+
+/// Grows a job's cells for its epochs, on whichever thread it ends up.
+pub fn run(job: &TileJob) -> TileResult {
+    let Rect { x, y, w, h } = job.rect;
+    let topology = job.topology.build(w, h);
+    let global = |local: usize| ((y + local / w) * job.width + x + local % w) as u32;
+
+    let mut collapsed = job.collapsed.0.clone();
+    let mut hsl = vec![[0.0f32; 3]; w * h];
+    let mut ring = Vec::with_capacity(job.ring.0.len());
+    for &(i, col) in &job.ring.0 {
+        hsl[i as usize] = col;
+        ring.push(i as usize);
+    }
+
+    let mut queued = BitSet::new(w * h);
+    let mut cells = Vec::with_capacity(job.epochs);
+    for e in 0..job.epochs {
+        let epoch = job.epoch + e as u32;
+        let mut targets = vec![];
+        for &cur in &ring {
+            for n in topology.neighbours(cur).iter() {
+                if !collapsed.get(n) && !queued.get(n) {
+                    queued.set(n, true);
+                    targets.push(n);
+                }
+            }
+        }
+
+        // every colour from the state before the epoch, then write them
+        let grown: Vec<_> = targets
+            .iter()
+            .map(|&t| {
+                let lookup = |n: usize| collapsed.get(n).then(|| hsl[n].map(f64::from));
+                let (mean, sources) = mean_value(topology.as_ref(), w, t, lookup)
+                    .expect("target without collapsed neighbours");
+                let col = jitter(mean, &job.variation, &cell_rng(job.seed, epoch, global(t)));
+                (t, col, sources)
+            })
+            .collect();
+        for &(t, col, _) in &grown {
+            collapsed.set(t, true);
+            queued.set(t, false);
+            hsl[t] = col.map(|v| v as f32);
+        }
+
+        ring.extend_from_slice(&targets);
+        ring.retain(|&i| topology.neighbours(i).iter().any(|n| !collapsed.get(n)));
+        cells.push(
+            grown
+                .into_iter()
+                .filter(|&(t, ..)| job.tile.contains(x + t % w, y + t / w))
+                .map(|(t, col, sources)| (global(t), col, sources))
+                .collect(),
+        );
+    }
+    TileResult {
+        id: job.id,
+        cells: Packed(cells),
+    }
+}
+
+/// Runs `jobs` handed round-robin to `threads` threads, like the app's worker pool
+/// does on native targets.
+pub fn run_threaded(jobs: &[TileJob], threads: usize) -> Vec<TileResult> {
+    thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                s.spawn(move || {
+                    jobs.iter()
+                        .skip(t)
+                        .step_by(threads)
+                        .map(run)
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect()
+    })
+}
+
+/// Grows `field` by up to `epochs` parallel epochs at once on `threads` threads,
+/// stopping early once it is complete.
+pub fn grow_threaded(field: &mut WFCField, epochs: usize, threads: usize) -> Vec<ChangeSet> {
+    let results = run_threaded(&jobs(field, epochs), threads);
+    let mut sets = vec![];
+    for e in 0..epochs {
+        if field.completed().is_some() {
+            break;
+        }
+        sets.push(field.apply_tiles(&results, e));
+    }
+    sets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::topology::TopologyKind;
+
+    #[test]
+    fn threads_and_epochs_per_job_dont_change_the_result() {
+        for topology in [TopologyKind::Moore, TopologyKind::VonNeumann] {
+            let mut expected = WFCField::new(150, 90, 7);
+            expected.set_topology(topology.build(150, 90));
+            while expected.completed().is_none() {
+                expected.epoch_parallel();
+            }
+
+            for threads in [1, 2, 5] {
+                for epochs in [1, 3, JOB_EPOCHS] {
+                    let mut field = WFCField::new(150, 90, 7);
+                    field.set_topology(topology.build(150, 90));
+                    while field.completed().is_none() {
+                        grow_threaded(&mut field, epochs, threads);
+                    }
+                    assert_eq!(field.cells, expected.cells, "{threads} threads, {epochs} epochs");
+                    assert_eq!(field.epoch_idx, expected.epoch_idx);
+                }
+            }
+        }
+    }
+}
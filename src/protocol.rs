@@ -0,0 +1,412 @@
+use std::fmt;
+
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::parallel::{TileJob, TileResult};
+use crate::run::{Finished, GrowthMode, Progress, StopCondition};
+use crate::selection::Selection;
+use crate::storage::CellStore;
+use crate::topology::TopologyKind;
+use crate::types::{Hsl, Rgba};
+use crate::wfc_field::{CellState, Origin, Variation};
+
+/// Bumped whenever `Command` or `Update` change shape, so a stale worker script
+/// answers with an error instead of misreading messages.
+pub const PROTOCOL_VERSION: u16 = 11;
+
+/// Largest field the generator accepts, 4096x2048 or the like. A field takes about
+/// 30 bytes per cell and sending a snapshot holds a few copies of it at once, on
+/// both sides of the bridge, all within a wasm32 heap of at most 4 GiB.
+pub const MAX_CELLS: usize = 1 << 23;
+
+/// What the UI asks the `Generator` to do.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Command {
+    /// Replaces the field with a fresh one.
+    Reset {
+        width: usize,
+        height: usize,
+        seed: u64,
+        topology: TopologyKind,
+    },
+    /// Replaces the field with an image to inpaint, see `WFCField::from_image`.
+    Import {
+        width: usize,
+        height: usize,
+        rgba: Packed<Vec<u8>>,
+        key: Option<Rgba>,
+        seed: u64,
+        topology: TopologyKind,
+    },
+    /// Growth settings, used from the next epoch on.
+    Configure {
+        mode: GrowthMode,
+        /// How long a batch of epochs may run, at least one epoch runs per batch.
+        budget_ms: f64,
+        variation: Variation,
+    },
+    /// Replaces the conditions growth stops on, see `WFCField::finished`.
+    StopWhen(Vec<StopCondition>),
+    /// Reseeds the generator without touching the cells.
+    Seed(u64),
+    Start,
+    /// Stops once the epoch in progress is done.
+    Pause,
+    /// Runs this many epochs, then stops.
+    Step(usize),
+    /// Stops right away and ends the run, dropping parallel epochs that are still
+    /// out. `Start` then begins a new run from the current cells, timed from zero.
+    Cancel,
+    /// Asks for an `Update::Snapshot` of the whole field.
+    Snapshot,
+    /// Cells to add as `[top, right, bottom, left]`, all zero does nothing.
+    Extend([usize; 4]),
+    /// Regrows the cells inside the selection, see `WFCField::reroll`.
+    Reroll {
+        selection: Selection,
+        seed: u64,
+    },
+    Undo,
+    Redo,
+    JumpTo(usize),
+    /// The last snapshot or changes have been handled, send the next batch. The UI
+    /// sends these once per animation frame, which paces the growth.
+    Ack,
+    /// Results for the tiles of an `Update::Jobs`.
+    Tiles(Vec<TileResult>),
+}
+
+/// Everything about the generator's state the UI shows besides the cells.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    pub epoch: usize,
+    pub seed: u64,
+    pub running: bool,
+    /// Epochs still to run for a `Command::Step`.
+    pub steps: usize,
+    pub remaining: usize,
+    pub can_undo: bool,
+    pub can_redo: bool,
+    pub first_epoch: Option<usize>,
+    pub history_bytes: usize,
+    pub progress: Progress,
+    /// The last run was cancelled, the next start begins a new one.
+    pub cancelled: bool,
+}
+
+/// What the `Generator` streams back. Snapshots and changes are answered with
+/// `Command::Ack` once drawn.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Update {
+    /// The whole field, after it was replaced or resized or when asked for.
+    Snapshot {
+        width: usize,
+        height: usize,
+        topology: TopologyKind,
+        cells: Packed<CellStore>,
+        status: Status,
+    },
+    /// Index and new state of the cells written since the last update, to apply in
+    /// order with `WFCField::apply_writes`. They carry the stored HSL, so a copy of
+    /// the field ends up with exactly the values generation used.
+    Changes {
+        changes: Packed<Vec<(u32, CellState)>>,
+        status: Status,
+    },
+    /// The status changed without any cells changing.
+    Progress(Status),
+    /// Growth stopped because a stop condition was met.
+    Finished { finished: Finished, status: Status },
+    /// Tiles of a few parallel epochs to spread over the worker pool, answer with
+    /// `Command::Tiles`.
+    Jobs(Vec<TileJob>),
+    /// A command was rejected, nothing changed.
+    Error(ProtocolError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProtocolError {
+    /// The two sides were built from different versions.
+    Version { expected: u16, got: u16 },
+    /// A field size that is empty or has more than `MAX_CELLS` cells.
+    InvalidSize { width: usize, height: usize },
+    /// An image buffer that doesn't hold `width * height` pixels.
+    BufferSize { expected: usize, got: usize },
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::Version { expected, got } => {
+                write!(f, "protocol version {} but expected {}", got, expected)
+            }
+            ProtocolError::InvalidSize { width, height } => {
+                write!(f, "invalid field size {}x{}", width, height)
+            }
+            ProtocolError::BufferSize { expected, got } => {
+                write!(f, "image buffer has {} bytes but needs {}", got, expected)
+            }
+        }
+    }
+}
+
+/// Checks a field size before anything gets allocated for it.
+pub fn check_size(width: usize, height: usize) -> Result<(), ProtocolError> {
+    match width.checked_mul(height) {
+        Some(n) if n > 0 && n <= MAX_CELLS => Ok(()),
+        _ => Err(ProtocolError::InvalidSize { width, height }),
+    }
+}
+
+/// A `Command` tagged with the sender's protocol version.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    pub version: u16,
+    pub command: Command,
+}
+
+impl From<Command> for Request {
+    fn from(command: Command) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            command,
+        }
+    }
+}
+
+/// An `Update` tagged with the sender's protocol version.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    pub version: u16,
+    pub update: Update,
+}
+
+impl From<Update> for Response {
+    fn from(update: Update) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            update,
+        }
+    }
+}
+
+/// Fixed little-endian layout for the bulky parts of messages. Serde would go
+/// through them value by value; packed they cross the worker boundary as one
+/// byte blob.
+pub trait Pack: Sized {
+    fn pack(&self, out: &mut Vec<u8>);
+    /// Reads a value back, advancing `buf`. `None` if it is cut short.
+    fn unpack(buf: &mut &[u8]) -> Option<Self>;
+}
+
+fn take<const N: usize>(buf: &mut &[u8]) -> Option<[u8; N]> {
+    let (head, rest) = buf.split_first_chunk::<N>()?;
+    *buf = rest;
+    Some(*head)
+}
+
+macro_rules! pack_le {
+    ($($t:ty),*) => {$(
+        impl Pack for $t {
+            fn pack(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn unpack(buf: &mut &[u8]) -> Option<Self> {
+                take(buf).map(<$t>::from_le_bytes)
+            }
+        }
+    )*};
+}
+
+pack_le!(u8, u32, u64, f32, f64);
+
+impl<T: Pack, const N: usize> Pack for [T; N] {
+    fn pack(&self, out: &mut Vec<u8>) {
+        self.iter().for_each(|v| v.pack(out));
+    }
+
+    fn unpack(buf: &mut &[u8]) -> Option<Self> {
+        let items = (0..N).map(|_| T::unpack(buf)).collect::<Option<Vec<_>>>()?;
+        items.try_into().ok()
+    }
+}
+
+impl<T: Pack> Pack for Vec<T> {
+    fn pack(&self, out: &mut Vec<u8>) {
+        (self.len() as u32).pack(out);
+        self.iter().for_each(|v| v.pack(out));
+    }
+
+    fn unpack(buf: &mut &[u8]) -> Option<Self> {
+        let len = u32::unpack(buf)? as usize;
+        // don't trust `len` with the allocation before the data is there
+        let mut out = Vec::with_capacity(len.min(buf.len()));
+        for _ in 0..len {
+            out.push(T::unpack(buf)?);
+        }
+        Some(out)
+    }
+}
+
+impl Pack for Origin {
+    fn pack(&self, out: &mut Vec<u8>) {
+        self.epoch.unwrap_or(u32::MAX).pack(out);
+        self.order.pack(out);
+        self.sources.pack(out);
+    }
+
+    fn unpack(buf: &mut &[u8]) -> Option<Self> {
+        Some(Origin {
+            epoch: Some(u32::unpack(buf)?).filter(|&e| e != u32::MAX),
+            order: u32::unpack(buf)?,
+            sources: u8::unpack(buf)?,
+        })
+    }
+}
+
+impl Pack for CellState {
+    fn pack(&self, out: &mut Vec<u8>) {
+        self.rgba.pack(out);
+        self.hsl.pack(out);
+        (self.collapsed as u8).pack(out);
+        self.origin.pack(out);
+    }
+
+    fn unpack(buf: &mut &[u8]) -> Option<Self> {
+        Some(CellState {
+            rgba: Rgba::unpack(buf)?,
+            hsl: Pack::unpack(buf)?,
+            collapsed: u8::unpack(buf)? != 0,
+            origin: Origin::unpack(buf)?,
+        })
+    }
+}
+
+/// A cell written in an `Update::Changes`.
+impl Pack for (u32, CellState) {
+    fn pack(&self, out: &mut Vec<u8>) {
+        self.0.pack(out);
+        self.1.pack(out);
+    }
+
+    fn unpack(buf: &mut &[u8]) -> Option<Self> {
+        Some((u32::unpack(buf)?, CellState::unpack(buf)?))
+    }
+}
+
+/// A generated cell in a `TileResult`: index, colour and `Origin::sources`.
+impl Pack for (u32, Hsl, u8) {
+    fn pack(&self, out: &mut Vec<u8>) {
+        self.0.pack(out);
+        self.1.pack(out);
+        self.2.pack(out);
+    }
+
+    fn unpack(buf: &mut &[u8]) -> Option<Self> {
+        Some((u32::unpack(buf)?, Hsl::unpack(buf)?, u8::unpack(buf)?))
+    }
+}
+
+/// A ring cell in a `TileJob`: index and stored HSL.
+impl Pack for (u32, [f32; 3]) {
+    fn pack(&self, out: &mut Vec<u8>) {
+        self.0.pack(out);
+        self.1.pack(out);
+    }
+
+    fn unpack(buf: &mut &[u8]) -> Option<Self> {
+        Some((u32::unpack(buf)?, Pack::unpack(buf)?))
+    }
+}
+
+/// Serializes `T` as a single byte blob through `Pack`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Packed<T>(pub T);
+
+impl<T: Pack> Serialize for Packed<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut out = vec![];
+        self.0.pack(&mut out);
+        serializer.serialize_bytes(&out)
+    }
+}
+
+impl<'de, T: Pack> Deserialize<'de> for Packed<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BytesVisitor;
+
+        impl<'de> Visitor<'de> for BytesVisitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("packed bytes")
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+                Ok(v.to_vec())
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+                Ok(v)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut out = vec![];
+                while let Some(b) = seq.next_element()? {
+                    out.push(b);
+                }
+                Ok(out)
+            }
+        }
+
+        let bytes = deserializer.deserialize_byte_buf(BytesVisitor)?;
+        let mut buf = bytes.as_slice();
+        match T::unpack(&mut buf) {
+            Some(v) if buf.is_empty() => Ok(Packed(v)),
+            _ => Err(de::Error::custom("malformed packed payload")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wfc_field::WFCField;
+
+    fn writes() -> Vec<(u32, CellState)> {
+        let mut field = WFCField::new(20, 20, 3);
+        (0..4).flat_map(|_| field.epoch3().writes().collect::<Vec<_>>()).collect()
+    }
+
+    #[test]
+    fn packed_values_round_trip() {
+        let writes = writes();
+        let json = serde_json::to_string(&Packed(writes.clone())).unwrap();
+        let back: Packed<Vec<(u32, CellState)>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.0, writes);
+
+        let mut field = WFCField::new(20, 20, 3);
+        field.epoch3();
+        let json = serde_json::to_string(&Packed(field.cells.clone())).unwrap();
+        let back: Packed<CellStore> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.0, field.cells);
+    }
+
+    #[test]
+    fn truncated_payloads_are_rejected() {
+        let mut bytes = vec![];
+        writes().pack(&mut bytes);
+        for len in 0..bytes.len() {
+            let mut buf = &bytes[..len];
+            assert!(Vec::<(u32, CellState)>::unpack(&mut buf).is_none(), "{len} bytes");
+        }
+
+        let short = serde_json::to_string(&bytes[..bytes.len() - 1]).unwrap();
+        assert!(serde_json::from_str::<Packed<Vec<(u32, CellState)>>>(&short).is_err());
+        bytes.push(0);
+        let long = serde_json::to_string(&bytes).unwrap();
+        assert!(serde_json::from_str::<Packed<Vec<(u32, CellState)>>>(&long).is_err());
+    }
+}
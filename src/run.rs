@@ -0,0 +1,254 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::wfc_field::{ChangeSet, WFCField};
+
+/// How far a run has got, as reported alongside the generator's status.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    pub collapsed: usize,
+    pub total: usize,
+    /// Cells growth continues from.
+    pub frontier: usize,
+    /// Time spent running, pauses not included.
+    pub elapsed_ms: f64,
+    /// Time left at the rate cells collapsed so far, `None` before there is one.
+    pub eta_ms: Option<f64>,
+}
+
+impl Progress {
+    /// Share of collapsed cells, in `0.0..=1.0`.
+    pub fn fraction(&self) -> f64 {
+        match self.total {
+            0 => 1.0,
+            total => self.collapsed as f64 / total as f64,
+        }
+    }
+}
+
+/// Which epoch function drives growth, switchable between epochs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GrowthMode {
+    Epoch,
+    Epoch2,
+    #[default]
+    Epoch3,
+    Constrained,
+    /// `epoch3` split into tiles; the generator hands them to the worker pool,
+    /// relayed through the main thread, several epochs at a time. Merging the
+    /// results alone costs most of an `Epoch3`, see the `growth` bench.
+    Parallel,
+}
+
+impl GrowthMode {
+    pub const ALL: [GrowthMode; 5] = [
+        GrowthMode::Epoch,
+        GrowthMode::Epoch2,
+        GrowthMode::Epoch3,
+        GrowthMode::Constrained,
+        GrowthMode::Parallel,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            GrowthMode::Epoch => "epoch",
+            GrowthMode::Epoch2 => "epoch2",
+            GrowthMode::Epoch3 => "epoch3",
+            GrowthMode::Constrained => "constrained",
+            GrowthMode::Parallel => "parallel",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|m| m.name() == name)
+    }
+
+    /// Runs one epoch on this thread.
+    pub fn epoch(&self, field: &mut WFCField) -> ChangeSet {
+        match self {
+            GrowthMode::Epoch => field.epoch(),
+            GrowthMode::Epoch2 => field.epoch2(),
+            GrowthMode::Epoch3 => field.epoch3(),
+            GrowthMode::Constrained => field.epoch_constrained(),
+            GrowthMode::Parallel => field.epoch_parallel(),
+        }
+    }
+}
+
+/// When growth stops on its own, checked between epochs.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopCondition {
+    /// Every cell is collapsed.
+    AllCollapsed,
+    /// Nothing is left to grow from, even with cells still uncollapsed.
+    FrontierEmpty,
+    /// `WFCField::epoch_idx` reached this epoch.
+    MaxEpochs(usize),
+    /// The run took this many milliseconds, pauses not included.
+    TimeBudget(f64),
+    /// At least this share of cells, in `0.0..=1.0`, is collapsed.
+    Coverage(f64),
+}
+
+impl StopCondition {
+    /// What a field starts with. Growth can't go on past either, so they always
+    /// apply on top of a field's own conditions.
+    pub const DEFAULT: [StopCondition; 2] =
+        [StopCondition::AllCollapsed, StopCondition::FrontierEmpty];
+
+    pub fn is_met(&self, field: &WFCField, elapsed_ms: f64) -> bool {
+        match *self {
+            StopCondition::AllCollapsed => field.is_complete(),
+            StopCondition::FrontierEmpty => field.frontier().is_empty(),
+            StopCondition::MaxEpochs(epochs) => field.epoch_idx >= epochs,
+            StopCondition::TimeBudget(ms) => elapsed_ms >= ms,
+            StopCondition::Coverage(share) => {
+                let collapsed = field.len() - field.remaining();
+                collapsed as f64 >= share * field.len() as f64
+            }
+        }
+    }
+}
+
+impl fmt::Display for StopCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopCondition::AllCollapsed => write!(f, "all cells collapsed"),
+            StopCondition::FrontierEmpty => write!(f, "frontier empty"),
+            StopCondition::MaxEpochs(epochs) => write!(f, "reached epoch {}", epochs),
+            StopCondition::TimeBudget(ms) => write!(f, "ran for {:.1} s", ms / 1000.0),
+            StopCondition::Coverage(share) => write!(f, "{:.1}% collapsed", share * 100.0),
+        }
+    }
+}
+
+/// A run that stopped because one of its `StopCondition`s was met.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Finished {
+    pub condition: StopCondition,
+    pub epoch: usize,
+}
+
+/// Shared flag to stop a run between epochs. Clones can be handed to whoever may
+/// want to cancel, on any thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Keeps time for a run across pauses and hands out its `CancelToken`. Loops that
+/// drive growth check `is_cancelled` between epochs, so cancelling never leaves an
+/// epoch half done.
+pub struct RunController {
+    /// Milliseconds since some fixed point, `js_sys::Date::now` by default.
+    clock: fn() -> f64,
+    token: CancelToken,
+    /// Time spent running before the current stretch.
+    elapsed: f64,
+    /// Start of the current stretch, while running.
+    since: Option<f64>,
+    /// Collapsed cells when the run started, to get the rate from.
+    baseline: Option<usize>,
+}
+
+impl Default for RunController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RunController {
+    pub fn new() -> Self {
+        Self::with_clock(js_sys::Date::now)
+    }
+
+    /// Uses `clock` instead of the JS one, e.g. to drive runs on native targets.
+    pub fn with_clock(clock: fn() -> f64) -> Self {
+        Self {
+            clock,
+            token: CancelToken::default(),
+            elapsed: 0.0,
+            since: None,
+            baseline: None,
+        }
+    }
+
+    /// Forgets the current run, for a new field.
+    pub fn reset(&mut self) {
+        self.token = CancelToken::default();
+        self.elapsed = 0.0;
+        self.since = None;
+        self.baseline = None;
+    }
+
+    /// Starts or continues the run with `collapsed` cells done so far. A cancelled
+    /// run is over, this starts a new one with a fresh token and clock.
+    pub fn resume(&mut self, collapsed: usize) {
+        if self.token.is_cancelled() {
+            self.reset();
+        }
+        self.since.get_or_insert((self.clock)());
+        self.baseline.get_or_insert(collapsed);
+    }
+
+    /// Stops the clock until the next `resume`.
+    pub fn pause(&mut self) {
+        if let Some(since) = self.since.take() {
+            self.elapsed += (self.clock)() - since;
+        }
+    }
+
+    /// Pauses and cancels the token handed out for the current run, ending it.
+    pub fn cancel(&mut self) {
+        self.pause();
+        self.token.cancel();
+    }
+
+    pub fn token(&self) -> CancelToken {
+        self.token.clone()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.since.is_some()
+    }
+
+    pub fn elapsed_ms(&self) -> f64 {
+        self.elapsed + self.since.map_or(0.0, |since| (self.clock)() - since)
+    }
+
+    pub fn progress(&self, field: &WFCField) -> Progress {
+        let total = field.len();
+        let collapsed = total - field.remaining();
+        let elapsed_ms = self.elapsed_ms();
+        let grown = collapsed.saturating_sub(self.baseline.unwrap_or(collapsed));
+        let eta_ms = match field.remaining() {
+            0 => Some(0.0),
+            _ if grown == 0 || elapsed_ms <= 0.0 => None,
+            left => Some(left as f64 * elapsed_ms / grown as f64),
+        };
+        Progress {
+            collapsed,
+            total,
+            frontier: field.frontier().len(),
+            elapsed_ms,
+            eta_ms,
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+
+use crate::types::Index;
+
+/// A region of the field picked on the canvas, in cell coordinates. Sent to the
+/// generator as is, it's much smaller than the cells it covers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Selection {
+    Rect((f64, f64), (f64, f64)),
+    Lasso(Vec<(f64, f64)>),
+}
+
+impl Selection {
+    /// All cells whose centre lies inside the selection, clipped to the field.
+    pub fn cells(&self, width: usize, height: usize) -> Vec<Index> {
+        let points = match self {
+            Selection::Rect(a, b) => vec![*a, *b],
+            Selection::Lasso(points) => points.clone(),
+        };
+        if points.is_empty() {
+            return vec![];
+        }
+
+        // Only scan the bounding box of the selection.
+        let (min, max) = points.iter().fold(
+            ((f64::MAX, f64::MAX), (f64::MIN, f64::MIN)),
+            |(min, max), &(x, y)| ((min.0.min(x), min.1.min(y)), (max.0.max(x), max.1.max(y))),
+        );
+        let clamp = |v: f64, len: usize| (v.max(0.0) as usize).min(len);
+
+        let mut cells = vec![];
+        for y in clamp(min.1.floor(), height)..clamp(max.1.ceil(), height) {
+            for x in clamp(min.0.floor(), width)..clamp(max.0.ceil(), width) {
+                if self.contains((x as f64 + 0.5, y as f64 + 0.5)) {
+                    cells.push((x, y));
+                }
+            }
+        }
+        cells
+    }
+
+    pub fn contains(&self, (px, py): (f64, f64)) -> bool {
+        match self {
+            Selection::Rect(a, b) => {
+                let (x0, x1) = (a.0.min(b.0), a.0.max(b.0));
+                let (y0, y1) = (a.1.min(b.1), a.1.max(b.1));
+                px >= x0 && px <= x1 && py >= y0 && py <= y1
+            }
+            // Even-odd ray casting.
+            Selection::Lasso(points) => {
+                let mut inside = false;
+                let mut j = points.len().wrapping_sub(1);
+                for (i, &(xi, yi)) in points.iter().enumerate() {
+                    let (xj, yj) = points[j];
+                    if (yi > py) != (yj > py) && px < (xj - xi) * (py - yi) / (yj - yi) + xi {
+                        inside = !inside;
+                    }
+                    j = i;
+                }
+                inside
+            }
+        }
+    }
+
+    /// Adds a point while dragging; rectangles only keep their last corner.
+    pub fn push(&mut self, p: (f64, f64)) {
+        match self {
+            Selection::Rect(_, b) => *b = p,
+            Selection::Lasso(points) => points.push(p),
+        }
+    }
+
+    pub fn points(&self) -> Vec<(f64, f64)> {
+        match self {
+            Selection::Rect(a, b) => vec![*a, (b.0, a.1), *b, (a.0, b.1)],
+            Selection::Lasso(points) => points.clone(),
+        }
+    }
+}
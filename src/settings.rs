@@ -0,0 +1,237 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::heatmap::{Blank, RenderMode};
+use crate::protocol::check_size;
+use crate::run::{GrowthMode, StopCondition};
+use crate::topology::TopologyKind;
+use crate::viewport::ZOOM_LEVELS;
+use crate::wfc_field::{Variation, WFCField};
+
+/// A complete recipe for a texture: the field, the rules colours grow by, when growth
+/// stops and how the result is shown. The web app and the `wfc` binary read the same
+/// files, as TOML or JSON; missing keys take their defaults:
+///
+/// ```toml
+/// width = 256
+/// height = 128
+/// seed = 42
+/// topology = "von_neumann"
+/// growth = "epoch3"
+/// stop = ["all_collapsed", { max_epochs = 500 }]
+///
+/// [variation]
+/// hue = [-10.0, 10.0]
+///
+/// [output]
+/// scale = 2.0
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Field size in cells.
+    pub width: usize,
+    pub height: usize,
+    /// Picks the starting cell and every colour after it. TOML can't hold seeds
+    /// past `i64::MAX`.
+    pub seed: u64,
+    /// Which cells count as neighbours.
+    pub topology: TopologyKind,
+    /// How far colours may stray from their neighbours.
+    pub variation: Variation,
+    /// Epoch function growth runs with.
+    pub growth: GrowthMode,
+    /// Growth stops once any of these is met, and always once the field is complete
+    /// or nothing is left to grow.
+    pub stop: Vec<StopCondition>,
+    pub output: Output,
+}
+
+/// How a grown field is shown in the app or written out as an image.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Output {
+    /// Screen pixels per cell in the app, within `ZOOM_LEVELS`. Images are scaled up
+    /// by the whole part of it, at least one pixel per cell.
+    pub scale: f64,
+    pub mode: RenderMode,
+    pub blank: Blank,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self {
+            scale: 1.0,
+            mode: RenderMode::default(),
+            blank: Blank::default(),
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            width: 300,
+            height: 300,
+            seed: 0,
+            topology: TopologyKind::Moore,
+            variation: Variation::default(),
+            growth: GrowthMode::default(),
+            stop: StopCondition::DEFAULT.to_vec(),
+            output: Output::default(),
+        }
+    }
+}
+
+/// File formats settings can be stored in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// The format a file name's extension stands for.
+    pub fn from_path(path: &str) -> Result<Self, SettingsError> {
+        let ext = path
+            .rsplit_once('.')
+            .map(|(_, ext)| ext.to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("toml") => Ok(Format::Toml),
+            Some("json") => Ok(Format::Json),
+            _ => Err(SettingsError::Format(path.to_string())),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Toml => "toml",
+            Format::Json => "json",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SettingsError {
+    /// Not a `.toml` or `.json` file.
+    Format(String),
+    /// The text isn't valid settings in its format, or the settings can't be
+    /// written in it.
+    Encoding(String),
+    /// An empty field, or one with more cells than the generator takes.
+    Size { width: usize, height: usize },
+    /// A variation range that is reversed, not finite or out of bounds.
+    Range {
+        channel: &'static str,
+        range: (f64, f64),
+    },
+    /// A stop condition that makes no sense, e.g. more than full coverage.
+    Stop(StopCondition),
+    /// A scale outside the app's zoom levels.
+    Scale(f64),
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SettingsError::Format(path) => {
+                write!(f, "{}: settings have to be .toml or .json", path)
+            }
+            SettingsError::Encoding(e) => write!(f, "invalid settings: {}", e),
+            SettingsError::Size { width, height } => {
+                write!(f, "invalid field size {}x{}", width, height)
+            }
+            SettingsError::Range { channel, range } => {
+                write!(f, "invalid {} range {:?}", channel, range)
+            }
+            SettingsError::Stop(condition) => write!(f, "invalid stop condition {:?}", condition),
+            SettingsError::Scale(scale) => write!(f, "invalid scale {}", scale),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+impl Settings {
+    /// Reads and validates settings.
+    pub fn parse(text: &str, format: Format) -> Result<Self, SettingsError> {
+        let settings: Settings = match format {
+            Format::Toml => toml::from_str(text).map_err(|e| e.to_string()),
+            Format::Json => serde_json::from_str(text).map_err(|e| e.to_string()),
+        }
+        .map_err(SettingsError::Encoding)?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn to_text(&self, format: Format) -> Result<String, SettingsError> {
+        match format {
+            Format::Toml => toml::to_string(self).map_err(|e| e.to_string()),
+            Format::Json => serde_json::to_string_pretty(self).map_err(|e| e.to_string()),
+        }
+        .map_err(SettingsError::Encoding)
+    }
+
+    /// One line of JSON for page links, read back with `Settings::parse`.
+    pub fn to_fragment(&self) -> Result<String, SettingsError> {
+        serde_json::to_string(self).map_err(|e| SettingsError::Encoding(e.to_string()))
+    }
+
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        check_size(self.width, self.height).map_err(|_| SettingsError::Size {
+            width: self.width,
+            height: self.height,
+        })?;
+
+        let Variation {
+            hue,
+            saturation,
+            lightness,
+        } = self.variation;
+        let ranges = [
+            ("hue", hue, -360.0, 360.0),
+            ("saturation", saturation, 0.0, 1.0),
+            ("lightness", lightness, -1.0, 1.0),
+        ];
+        for (channel, range, min, max) in ranges {
+            if !(min <= range.0 && range.0 <= range.1 && range.1 <= max) {
+                return Err(SettingsError::Range { channel, range });
+            }
+        }
+
+        for &condition in &self.stop {
+            let valid = match condition {
+                StopCondition::TimeBudget(ms) => ms.is_finite() && ms >= 0.0,
+                StopCondition::Coverage(share) => (0.0..=1.0).contains(&share),
+                _ => true,
+            };
+            if !valid {
+                return Err(SettingsError::Stop(condition));
+            }
+        }
+
+        let (min, max) = (ZOOM_LEVELS[0], ZOOM_LEVELS[ZOOM_LEVELS.len() - 1]);
+        if !(min..=max).contains(&self.output.scale) {
+            return Err(SettingsError::Scale(self.output.scale));
+        }
+        Ok(())
+    }
+
+    /// Whether going from `self` to `other` needs a new field.
+    pub fn rebuilds(&self, other: &Settings) -> bool {
+        (self.width, self.height, self.seed, self.topology)
+            != (other.width, other.height, other.seed, other.topology)
+    }
+
+    /// A fresh field for these settings, ready to grow.
+    pub fn build(&self) -> WFCField {
+        let mut field = WFCField::new(self.width, self.height, self.seed);
+        if self.topology != field.topology().kind() {
+            field.set_topology(self.topology.build(self.width, self.height));
+        }
+        field.set_variation(self.variation);
+        field.set_stop_conditions(self.stop.clone());
+        field
+    }
+}
@@ -0,0 +1,127 @@
+use crate::frontier::BitSet;
+use crate::protocol::Pack;
+use crate::types::{Hsl, Rgba};
+use crate::wfc_field::{Origin, Pixel};
+
+/// Cell data as separate contiguous buffers: RGBA bytes laid out like `ImageData`,
+/// HSL in `f32` for generation, a collapsed bit per cell, and each cell's `Origin`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CellStore {
+    rgba: Vec<u8>,
+    hsl: Vec<[f32; 3]>,
+    collapsed: BitSet,
+    /// Collapse epoch per cell, `u32::MAX` for none.
+    epochs: Vec<u32>,
+    orders: Vec<u32>,
+    sources: Vec<u8>,
+}
+
+impl CellStore {
+    /// `len` uncollapsed cells.
+    pub fn new(len: usize) -> Self {
+        let blank = Pixel::new();
+        Self {
+            rgba: blank.rgba.repeat(len),
+            hsl: vec![blank.hsl.map(|v| v as f32); len],
+            collapsed: BitSet::new(len),
+            epochs: vec![u32::MAX; len],
+            orders: vec![0; len],
+            sources: vec![0; len],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.hsl.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hsl.is_empty()
+    }
+
+    pub fn is_collapsed(&self, idx: usize) -> bool {
+        self.collapsed.get(idx)
+    }
+
+    pub fn collapsed_count(&self) -> usize {
+        self.collapsed.count_ones()
+    }
+
+    pub fn rgba(&self, idx: usize) -> Rgba {
+        self.rgba[idx * 4..idx * 4 + 4].try_into().unwrap()
+    }
+
+    pub fn hsl(&self, idx: usize) -> Hsl {
+        self.hsl[idx].map(|v| v as f64)
+    }
+
+    pub fn pixel(&self, idx: usize) -> Pixel {
+        Pixel {
+            rgba: self.rgba(idx),
+            hsl: self.hsl(idx),
+        }
+    }
+
+    pub fn set(&mut self, idx: usize, collapsed: bool, px: &Pixel) {
+        self.rgba[idx * 4..idx * 4 + 4].copy_from_slice(&px.rgba);
+        self.hsl[idx] = px.hsl.map(|v| v as f32);
+        self.collapsed.set(idx, collapsed);
+    }
+
+    pub fn origin(&self, idx: usize) -> Origin {
+        Origin {
+            epoch: Some(self.epochs[idx]).filter(|&e| e != u32::MAX),
+            order: self.orders[idx],
+            sources: self.sources[idx],
+        }
+    }
+
+    pub fn set_origin(&mut self, idx: usize, origin: Origin) {
+        self.epochs[idx] = origin.epoch.unwrap_or(u32::MAX);
+        self.orders[idx] = origin.order;
+        self.sources[idx] = origin.sources;
+    }
+
+    /// Copies cell `from` of `other` into cell `to`.
+    pub fn copy_from(&mut self, other: &CellStore, from: usize, to: usize) {
+        self.rgba[to * 4..to * 4 + 4].copy_from_slice(&other.rgba[from * 4..from * 4 + 4]);
+        self.hsl[to] = other.hsl[from];
+        self.collapsed.set(to, other.collapsed.get(from));
+        self.epochs[to] = other.epochs[from];
+        self.orders[to] = other.orders[from];
+        self.sources[to] = other.sources[from];
+    }
+
+    /// The whole field as RGBA bytes, row-major.
+    pub fn rgba_bytes(&self) -> &[u8] {
+        &self.rgba
+    }
+}
+
+impl Pack for CellStore {
+    fn pack(&self, out: &mut Vec<u8>) {
+        self.rgba.pack(out);
+        self.hsl.pack(out);
+        self.collapsed.pack(out);
+        self.epochs.pack(out);
+        self.orders.pack(out);
+        self.sources.pack(out);
+    }
+
+    fn unpack(buf: &mut &[u8]) -> Option<Self> {
+        let store = Self {
+            rgba: Pack::unpack(buf)?,
+            hsl: Pack::unpack(buf)?,
+            collapsed: Pack::unpack(buf)?,
+            epochs: Pack::unpack(buf)?,
+            orders: Pack::unpack(buf)?,
+            sources: Pack::unpack(buf)?,
+        };
+        let len = store.len();
+        let consistent = store.rgba.len() == len * 4
+            && store.collapsed.len() == len
+            && store.epochs.len() == len
+            && store.orders.len() == len
+            && store.sources.len() == len;
+        consistent.then_some(store)
+    }
+}
@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+
+pub const MAX_NEIGHBOURS: usize = 8;
+
+/// Up to `MAX_NEIGHBOURS` cell indices, stored inline so looking them up never allocates.
+#[derive(Debug, Clone, Copy)]
+pub struct Neighbours {
+    cells: [u32; MAX_NEIGHBOURS],
+    len: u8,
+}
+
+impl Neighbours {
+    fn new() -> Self {
+        Self {
+            cells: [0; MAX_NEIGHBOURS],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, idx: usize) {
+        self.cells[self.len as usize] = idx as u32;
+        self.len += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u32] {
+        &self.cells[..self.len as usize]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.as_slice().iter().map(|&i| i as usize)
+    }
+}
+
+/// Which cells count as adjacent. Neighbours are computed on demand from the
+/// field size, so memory doesn't grow with the number of cells.
+pub trait Topology {
+    /// Neighbours of the cell at `idx` (row-major), always in the same order.
+    fn neighbours(&self, idx: usize) -> Neighbours;
+
+    /// The same topology for a field of a different size.
+    fn resized(&self, width: usize, height: usize) -> Box<dyn Topology>;
+
+    fn kind(&self) -> TopologyKind;
+}
+
+/// The built-in topologies, for sending one to a worker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TopologyKind {
+    Moore,
+    VonNeumann,
+}
+
+impl TopologyKind {
+    pub const ALL: [TopologyKind; 2] = [TopologyKind::Moore, TopologyKind::VonNeumann];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TopologyKind::Moore => "Moore",
+            TopologyKind::VonNeumann => "Von Neumann",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.name() == name)
+    }
+
+    pub fn build(&self, width: usize, height: usize) -> Box<dyn Topology> {
+        match self {
+            TopologyKind::Moore => Box::new(Moore { width, height }),
+            TopologyKind::VonNeumann => Box::new(VonNeumann { width, height }),
+        }
+    }
+}
+
+/// Offsets within a 3x3 block, column by column, so the order matches across topologies.
+fn grid_neighbours(
+    width: usize,
+    height: usize,
+    idx: usize,
+    include: impl Fn(i64, i64) -> bool,
+) -> Neighbours {
+    let (x, y) = ((idx % width) as i64, (idx / width) as i64);
+    let mut neighs = Neighbours::new();
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            let (xn, yn) = (x + dx, y + dy);
+            if (dx == 0 && dy == 0)
+                || !include(dx, dy)
+                || xn < 0
+                || yn < 0
+                || xn >= width as i64
+                || yn >= height as i64
+            {
+                continue;
+            }
+            neighs.push(yn as usize * width + xn as usize);
+        }
+    }
+    neighs
+}
+
+/// 8-neighbourhood, including diagonals.
+#[derive(Debug, Clone, Copy)]
+pub struct Moore {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Topology for Moore {
+    fn neighbours(&self, idx: usize) -> Neighbours {
+        grid_neighbours(self.width, self.height, idx, |_, _| true)
+    }
+
+    fn resized(&self, width: usize, height: usize) -> Box<dyn Topology> {
+        Box::new(Moore { width, height })
+    }
+
+    fn kind(&self) -> TopologyKind {
+        TopologyKind::Moore
+    }
+}
+
+/// 4-neighbourhood, edges only.
+#[derive(Debug, Clone, Copy)]
+pub struct VonNeumann {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Topology for VonNeumann {
+    fn neighbours(&self, idx: usize) -> Neighbours {
+        grid_neighbours(self.width, self.height, idx, |dx, dy| dx == 0 || dy == 0)
+    }
+
+    fn resized(&self, width: usize, height: usize) -> Box<dyn Topology> {
+        Box::new(VonNeumann { width, height })
+    }
+
+    fn kind(&self) -> TopologyKind {
+        TopologyKind::VonNeumann
+    }
+}
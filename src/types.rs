@@ -1,35 +1,74 @@
+use std::cell::Cell;
 use std::ops::Range;
 
 use gloo::console::log;
 
-pub type Settings = (usize,);
 pub type Index = (usize, usize);
 pub type Hsl = [f64; 3];
 pub type Rgba = [u8; 4];
 
-pub struct Rand {}
+/// Small seedable generator (SplitMix64) so a seed always reproduces the same field.
+pub struct Rand {
+    state: Cell<u64>,
+}
+
 impl Rand {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Cell::new(seed),
+        }
+    }
+
+    /// A fresh seed from the browser's `Math.random`.
+    pub fn random_seed() -> u64 {
+        (js_sys::Math::random() * u32::MAX as f64) as u64
+    }
+
+    pub fn reseed(&self, seed: u64) {
+        self.state.set(seed);
+    }
+
+    /// Where the sequence is at, `reseed` with it to continue from here.
+    pub fn state(&self) -> u64 {
+        self.state.get()
+    }
+
+    pub fn next_u64(&self) -> u64 {
+        let state = self.state.get().wrapping_add(0x9E3779B97F4A7C15);
+        self.state.set(state);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub fn random(&self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
     pub fn map_range(v: f64, in_min: f64, in_max: f64, out_min: f64, out_max: f64) -> f64 {
         let slope = (out_max - out_min) / (in_max - in_min);
         out_min + slope * (v - in_min)
     }
 
-    pub fn gen_rangei32(range: Range<i32>) -> f64 {
+    pub fn gen_rangei32(&self, range: Range<i32>) -> f64 {
         let iter = range.into_iter();
         let max = iter.clone().max().unwrap();
         let min = iter.min().unwrap();
-        let v = js_sys::Math::random();
+        let v = self.random();
         Rand::map_range(v, 0.0, 1.0, min as f64, max as f64)
     }
 
-    pub fn gen_rangef64(start:f64, end:f64) -> f64 {
+    pub fn gen_rangef64(&self, start:f64, end:f64) -> f64 {
         let max = end;
         let min = start;
-        let v = js_sys::Math::random();
-        Rand::map_range(v, 0.0, 1.0, min as f64, max as f64)
+        let v = self.random();
+        Rand::map_range(v, 0.0, 1.0, min, max)
     }
 }
 
+#[derive(Default)]
 pub struct JSTimer {
     start: f64,
     epochs: Vec<f64>
@@ -44,6 +83,11 @@ impl JSTimer {
         self.start = js_sys::Date::now();
     }
 
+    /// Milliseconds since `start_time`, without logging.
+    pub fn elapsed_ms(&self) -> f64 {
+        js_sys::Date::now() - self.start
+    }
+
     pub fn epoch_from_start(&mut self, msg:&str) {
         let epoch = js_sys::Date::now();
         let time = (epoch - self.start) / 1000.0;
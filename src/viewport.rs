@@ -0,0 +1,107 @@
+use crate::dirty::Rect;
+
+/// Zoom steps in screen pixels per cell. Integer levels from 1 up keep cells
+/// pixel-perfect; the fractional ones below let large fields fit on screen.
+pub const ZOOM_LEVELS: [f64; 16] = [
+    0.125, 0.25, 0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 8.0, 10.0, 12.0, 16.0, 24.0, 32.0, 64.0,
+];
+
+/// Maps between screen pixels and cell coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    /// Screen pixels per cell.
+    pub zoom: f64,
+    /// Cell coordinate shown at the top-left corner of the screen.
+    pub offset: (f64, f64),
+    /// Screen size in pixels.
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Viewport {
+    pub fn new(width: f64, height: f64, zoom: f64) -> Self {
+        Self {
+            zoom,
+            offset: (0.0, 0.0),
+            width,
+            height,
+        }
+    }
+
+    pub fn to_cell(&self, (sx, sy): (f64, f64)) -> (f64, f64) {
+        (
+            self.offset.0 + sx / self.zoom,
+            self.offset.1 + sy / self.zoom,
+        )
+    }
+
+    pub fn to_screen(&self, (cx, cy): (f64, f64)) -> (f64, f64) {
+        (
+            ((cx - self.offset.0) * self.zoom).round(),
+            ((cy - self.offset.1) * self.zoom).round(),
+        )
+    }
+
+    /// Steps `steps` zoom levels in (positive) or out, keeping the cell under the
+    /// screen point `at` in place.
+    pub fn zoom_at(&mut self, at: (f64, f64), steps: i32) {
+        let cur = ZOOM_LEVELS
+            .iter()
+            .position(|&z| z >= self.zoom)
+            .unwrap_or(ZOOM_LEVELS.len() - 1) as i32;
+        let next = (cur + steps).clamp(0, ZOOM_LEVELS.len() as i32 - 1) as usize;
+        self.zoom_to(at, ZOOM_LEVELS[next]);
+    }
+
+    /// Sets the zoom, keeping the cell under the screen point `at` in place.
+    pub fn zoom_to(&mut self, at: (f64, f64), zoom: f64) {
+        let anchor = self.to_cell(at);
+        self.zoom = zoom;
+        self.offset = (anchor.0 - at.0 / zoom, anchor.1 - at.1 / zoom);
+    }
+
+    /// Moves the view by a drag of `dx`/`dy` screen pixels.
+    pub fn pan(&mut self, dx: f64, dy: f64) {
+        self.offset.0 -= dx / self.zoom;
+        self.offset.1 -= dy / self.zoom;
+    }
+
+    /// Largest zoom level that shows the whole field, centred.
+    pub fn fit(&mut self, field_w: usize, field_h: usize) {
+        let fit = (self.width / field_w as f64).min(self.height / field_h as f64);
+        self.zoom = ZOOM_LEVELS
+            .iter()
+            .rev()
+            .copied()
+            .find(|&z| z <= fit)
+            .unwrap_or(ZOOM_LEVELS[0]);
+        self.offset = (
+            (field_w as f64 - self.width / self.zoom) / 2.0,
+            (field_h as f64 - self.height / self.zoom) / 2.0,
+        );
+    }
+
+    pub fn resize(&mut self, width: f64, height: f64) {
+        self.width = width;
+        self.height = height;
+    }
+
+    /// Cells on screen, clipped to the field; `None` if the field is out of view.
+    pub fn visible(&self, field_w: usize, field_h: usize) -> Option<Rect> {
+        let (x0, y0) = self.to_cell((0.0, 0.0));
+        let (x1, y1) = self.to_cell((self.width, self.height));
+        let x0 = x0.floor().max(0.0) as usize;
+        let y0 = y0.floor().max(0.0) as usize;
+        let x1 = (x1.ceil().max(0.0) as usize).min(field_w);
+        let y1 = (y1.ceil().max(0.0) as usize).min(field_h);
+        if x0 >= x1 || y0 >= y1 {
+            return None;
+        }
+        Some(Rect {
+            x: x0,
+            y: y0,
+            w: x1 - x0,
+            h: y1 - y0,
+        })
+    }
+}
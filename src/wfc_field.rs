@@ -1,24 +1,104 @@
-use gloo::console::log;
+use serde::{Deserialize, Serialize};
 
-use crate::types::{Hsl, Index, JSTimer, Rand, Rgba};
+use crate::dirty::{DirtyTiles, Rect};
+use crate::frontier::Frontier;
+use crate::parallel::{self, TileResult};
+use crate::run::{Finished, StopCondition};
+use crate::storage::CellStore;
+use crate::topology::{Topology, TopologyKind, MAX_NEIGHBOURS};
+use crate::types::{Hsl, Index, Rand, Rgba};
 
 /// Rules
 /// - neighbour generation: hsl range of h = [-20, 20], l = [-10, 10]
 /// - possible neighbours
 ///
-///     [0]120,1,55        [3]95,1,50*1
-///     [1]100,1,50        [2]90,1,45
-///     [6]*2              [4]80,1,35
-///     [5]70,1,30         [5]70,1,30
+/// ```text
+/// [0]120,1,55        [3]95,1,50*1
+/// [1]100,1,50        [2]90,1,45
+/// [6]*2              [4]80,1,35
+/// [5]70,1,30         [5]70,1,30
+/// ```
 ///
 /// *1(90..120,1,45..55)
 /// *2(min(all)..max(all),1, min(all)..max(all))
+///
+/// How far a generated colour may stray from the mean of its neighbours, per HSL
+/// channel. Saturation isn't inherited, every cell draws it from its range.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Variation {
+    /// Degrees added to the mean hue.
+    pub hue: (f64, f64),
+    /// Absolute saturation, in `0.0..=1.0`.
+    pub saturation: (f64, f64),
+    /// Added to the mean lightness.
+    pub lightness: (f64, f64),
+}
+
+impl Default for Variation {
+    fn default() -> Self {
+        Self {
+            hue: (-20.0, 20.0),
+            saturation: (1.0, 1.0),
+            lightness: (-0.1, 0.1),
+        }
+    }
+}
+
+/// Average hue and lightness of the collapsed neighbours of `idx` in a grid `width`
+/// cells wide, the colour `jitter` varies around, and which neighbours went into it
+/// as `Origin::sources` bits. `hsl` gives a cell's colour if it is collapsed. `None`
+/// if no neighbour is collapsed yet.
+pub(crate) fn mean_value(
+    topology: &dyn Topology,
+    width: usize,
+    idx: usize,
+    hsl: impl Fn(usize) -> Option<Hsl>,
+) -> Option<(Hsl, u8)> {
+    let (x, y) = ((idx % width) as i64, (idx / width) as i64);
+    let mut sum: Option<Hsl> = None;
+    let mut cnt = 0.0;
+    let mut sources = 0;
+    for n in topology.neighbours(idx).iter() {
+        if let Some(col) = hsl(n) {
+            match &mut sum {
+                Some(acc) => {
+                    acc[0] += col[0];
+                    acc[2] += col[2];
+                }
+                None => sum = Some(col),
+            }
+            cnt += 1.0;
+            let offset = ((n % width) as i64 - x, (n / width) as i64 - y);
+            if let Some(bit) = NEIGHBOUR_OFFSETS.iter().position(|&o| o == offset) {
+                sources |= 1 << bit;
+            }
+        }
+    }
+    let sum = sum?;
+
+    Some(([sum[0] / cnt, sum[1], sum[2] / cnt], sources))
+}
 
-const BASIC_RANGES: ((i32, i32), (f64, f64)) = ((-20, 20), (-0.1, 0.1));
+/// A random colour within `variation` of `mean`.
+pub(crate) fn jitter(mean: Hsl, variation: &Variation, rng: &Rand) -> Hsl {
+    let Variation {
+        hue,
+        saturation,
+        lightness,
+    } = variation;
+    let h = mean[0] + rng.gen_rangef64(hue.0, hue.1);
+    let l = mean[2] + rng.gen_rangef64(lightness.0, lightness.1);
+    let s = match saturation.0 < saturation.1 {
+        true => rng.gen_rangef64(saturation.0, saturation.1),
+        false => saturation.0,
+    };
+    [h, s, l]
+}
 
 pub enum PixelType {
-    RGBA(Rgba),
-    HSL(Hsl),
+    Rgba(Rgba),
+    Hsl(Hsl),
 }
 
 #[derive(Debug, Clone)]
@@ -28,27 +108,26 @@ pub struct Pixel {
 }
 
 impl Pixel {
-    pub fn random() -> Self {
-        let f = || Rand::gen_rangei32(0..255);
-        let rgba = [f() as u8, f() as u8, f() as u8, 1];
+    pub fn random(rng: &Rand) -> Self {
+        let f = || rng.gen_rangei32(0..255);
+        let rgba = [f() as u8, f() as u8, f() as u8, 255];
         let hsl = Pixel::rgb2hsl(rgba[0], rgba[1], rgba[2]);
         Self { rgba, hsl }
     }
 
     pub fn new() -> Self {
-        let rgba = [0, 0, 0, 1];
+        let rgba = [0, 0, 0, 255];
         let hsl = Pixel::rgb2hsl(rgba[0], rgba[1], rgba[2]);
         Self { rgba, hsl }
     }
 
     pub fn set_data(&mut self, set: PixelType) {
-        use PixelType::*;
         match set {
-            RGBA(v) => {
+            PixelType::Rgba(v) => {
                 self.rgba = v;
                 self.hsl = Pixel::rgb2hsl(v[0], v[1], v[2]);
             }
-            HSL(v) => {
+            PixelType::Hsl(v) => {
                 self.hsl = v;
                 self.rgba = Pixel::hsl2rgb(v[0], v[1], v[2]);
             }
@@ -74,7 +153,7 @@ impl Pixel {
             };
         }
 
-        hue = hue * 60.0;
+        hue *= 60.0;
         if hue < 0.0 {
             hue += 360.0
         }
@@ -117,238 +196,717 @@ impl Pixel {
             };
             (l - a * min.max(-1.0)) * 255.0
         };
-        [f(0.0) as u8, f(8.0) as u8, f(4.0) as u8, 1]
+        [f(0.0) as u8, f(8.0) as u8, f(4.0) as u8, 255]
     }
 }
 
-pub struct Cell {
-    pub px: Pixel,
+impl Default for Pixel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Directions of the bits in `Origin::sources`, as `(dx, dy)`.
+pub const NEIGHBOUR_OFFSETS: [(i64, i64); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+/// How a cell came by its colour, so odd looking output can be traced back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Origin {
+    /// Epoch that collapsed the cell, `None` for imported and uncollapsed cells.
+    pub epoch: Option<u32>,
+    /// Position in the sequence of collapses since the field was created, 0 without an epoch.
+    pub order: u32,
+    /// Neighbours averaged into the colour, one bit per `NEIGHBOUR_OFFSETS` entry.
+    /// Empty with an epoch set for random seeds.
+    pub sources: u8,
+}
+
+impl Origin {
+    /// `(dx, dy)` of the neighbours that contributed to the colour.
+    pub fn source_offsets(&self) -> impl Iterator<Item = (i64, i64)> {
+        let sources = self.sources;
+        NEIGHBOUR_OFFSETS
+            .into_iter()
+            .enumerate()
+            .filter(move |(i, _)| sources & (1 << i) != 0)
+            .map(|(_, o)| o)
+    }
+}
+
+/// What a history entry needs to know about a cell, much smaller than a full `Pixel`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CellState {
+    pub rgba: Rgba,
+    /// As stored, generated colours can be out of range and don't survive a round
+    /// trip through `rgba`.
+    pub hsl: [f32; 3],
     pub collapsed: bool,
-    num_blank: u32,
+    pub origin: Origin,
 }
 
-impl Cell {
-    pub fn new() -> Self {
+impl CellState {
+    fn of(cells: &CellStore, idx: usize) -> Self {
         Self {
-            px: Pixel::new(),
+            rgba: cells.rgba(idx),
+            hsl: cells.hsl(idx).map(|v| v as f32),
+            collapsed: cells.is_collapsed(idx),
+            origin: cells.origin(idx),
+        }
+    }
+}
+
+impl CellState {
+    /// How every uncollapsed cell looks.
+    pub fn blank() -> Self {
+        let px = Pixel::new();
+        Self {
+            rgba: px.rgba,
+            hsl: px.hsl.map(|v| v as f32),
             collapsed: false,
-            num_blank: 8,
+            origin: Origin::default(),
         }
     }
 }
 
+/// What growth goes on from besides the cells. History restores it along with them,
+/// so growing again after an undo or redo takes the same path as the first time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GrowthState {
+    pub seed: u64,
+    /// State of the field's generator.
+    pub rng: u64,
+    /// See `Origin::order`.
+    pub collapses: u32,
+    /// Where `epoch2` walks on from.
+    pub last: Index,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CellChange {
+    pub idx: u32,
+    pub before: CellState,
+    pub after: CellState,
+}
+
+/// Everything one growth step or edit changed, so callers can redraw, record or
+/// stream just those cells instead of diffing `data`.
+#[derive(Debug, Clone, Default)]
+pub struct ChangeSet {
+    /// `epoch_idx` before the step.
+    pub epoch: usize,
+    /// Epochs the step advanced, 0 for edits.
+    pub epochs: usize,
+    pub changes: Vec<CellChange>,
+    /// Frontier size after the step.
+    pub frontier: usize,
+    /// Growth state before and after the step.
+    pub start: GrowthState,
+    pub end: GrowthState,
+}
+
+impl ChangeSet {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.epochs == 0
+    }
+
+    /// Changed cell indices with their new colours.
+    pub fn cells(&self) -> impl Iterator<Item = (usize, Rgba)> + '_ {
+        self.changes.iter().map(|c| (c.idx as usize, c.after.rgba))
+    }
+
+    /// Each changed cell's index and new state, what a copy of the field needs.
+    pub fn writes(&self) -> impl Iterator<Item = (u32, CellState)> + '_ {
+        self.changes.iter().map(|c| (c.idx, c.after))
+    }
+}
+
 pub struct WFCField {
-    pub data: Box<[Cell]>,
-    pub dim: usize,
+    pub cells: CellStore,
+    pub width: usize,
+    pub height: usize,
     pub epoch_idx: usize,
-    visited: Vec<Index>,
+    pub seed: u64,
+    rng: Rand,
+    frontier: Frontier,
     last: Index,
-    neighbours: Box<[Box<[Index]>]>,
+    topology: Box<dyn Topology>,
+    journal: Vec<CellChange>,
+    /// Growth state when the journal was last drained.
+    step_start: GrowthState,
+    /// Uncollapsed neighbours per cell, kept up to date on every collapse/reset.
+    num_blank: Vec<u8>,
+    remaining: usize,
+    dirty: DirtyTiles,
+    /// Cells collapsed by growth or seeding so far, for `Origin::order`.
+    collapses: u32,
+    /// Growth is done once any of these is met, or `StopCondition::DEFAULT`.
+    stop_conditions: Vec<StopCondition>,
+    variation: Variation,
 }
 
 impl WFCField {
-    pub fn new(dim: usize) -> Self {
-        let x = Rand::gen_rangei32(0..(dim + 1) as i32) as usize;
-        let y = Rand::gen_rangei32(0..(dim + 1) as i32) as usize;
+    pub fn new(width: usize, height: usize, seed: u64) -> Self {
+        let cells = CellStore::new(width * height);
+        let mut field = Self::with_cells(width, height, seed, cells, TopologyKind::Moore);
+        field.init();
+        field.start_step();
+        field
+    }
 
-        let idx = x * dim + y;
-        let mut visited = Vec::with_capacity(dim * 4);
-        visited.push((x, y));
+    /// Builds a field from an RGBA image buffer (row-major, 4 bytes per pixel).
+    /// Transparent pixels and pixels matching `key` are left uncollapsed, everything
+    /// else is taken over as collapsed cells, so growth only fills the holes.
+    pub fn from_image(
+        width: usize,
+        height: usize,
+        rgba: &[u8],
+        key: Option<Rgba>,
+        seed: u64,
+    ) -> Self {
+        assert!(rgba.len() >= width * height * 4, "image buffer too small");
+        let mut cells = CellStore::new(width * height);
+        for (idx, px) in rgba.chunks_exact(4).take(width * height).enumerate() {
+            let is_key = key.is_some_and(|k| px[..3] == k[..3]);
+            if px[3] != 0 && !is_key {
+                let mut pixel = Pixel::new();
+                pixel.set_data(PixelType::Rgba([px[0], px[1], px[2], 255]));
+                cells.set(idx, true, &pixel);
+            }
+        }
 
-        let mut data = (0..dim * dim)
-            .map(|_| Cell::new())
-            .collect::<Vec<_>>()
-            .into_boxed_slice();
-        data[idx].collapsed = true;
-        data[idx].px = Pixel::random();
-        let l = data.len();
+        let mut field = Self::with_cells(width, height, seed, cells, TopologyKind::Moore);
+        field.rebuild_frontier();
+        if field.cells.collapsed_count() == 0 {
+            field.init();
+        }
+        field.start_step();
+        field
+    }
 
-        let neighbours = WFCField::gen_neighbours(l, dim);
+    /// Takes over `cells` as they are, e.g. a snapshot of a field kept elsewhere.
+    pub fn from_cells(
+        width: usize,
+        height: usize,
+        seed: u64,
+        cells: CellStore,
+        topology: TopologyKind,
+    ) -> Self {
+        let mut field = Self::with_cells(width, height, seed, cells, topology);
+        field.rebuild_frontier();
+        field.start_step();
+        field
+    }
 
-        Self {
-            data,
-            dim,
+    fn with_cells(
+        width: usize,
+        height: usize,
+        seed: u64,
+        cells: CellStore,
+        topology: TopologyKind,
+    ) -> Self {
+        let mut field = Self {
+            cells,
+            width,
+            height,
             epoch_idx: 0,
-            visited,
-            neighbours,
-            last: (x, y),
+            seed,
+            rng: Rand::new(seed),
+            frontier: Frontier::new(width * height),
+            topology: topology.build(width, height),
+            last: (0, 0),
+            journal: vec![],
+            step_start: GrowthState::default(),
+            num_blank: vec![],
+            remaining: 0,
+            dirty: DirtyTiles::new(width, height),
+            collapses: 0,
+            stop_conditions: StopCondition::DEFAULT.to_vec(),
+            variation: Variation::default(),
+        };
+        field.recount();
+        field
+    }
+
+    /// Recomputes the uncollapsed neighbour counters from scratch.
+    fn recount(&mut self) {
+        self.num_blank = (0..self.len())
+            .map(|i| {
+                let neighs = self.topology.neighbours(i);
+                neighs
+                    .iter()
+                    .filter(|&n| !self.cells.is_collapsed(n))
+                    .count() as u8
+            })
+            .collect();
+        self.remaining = self.len() - self.cells.collapsed_count();
+    }
+
+    /// Grows the field by the given number of cells on each side, keeping the
+    /// existing content. Growth continues from the old border into the new area.
+    pub fn extend(&mut self, top: usize, right: usize, bottom: usize, left: usize) {
+        let width = self.width + left + right;
+        let height = self.height + top + bottom;
+
+        let mut cells = CellStore::new(width * height);
+        for i in 0..self.len() {
+            let (x, y) = self.pos(i);
+            cells.copy_from(&self.cells, i, (y + top) * width + x + left);
+        }
+
+        self.cells = cells;
+        self.width = width;
+        self.height = height;
+        self.topology = self.topology.resized(width, height);
+        self.dirty = DirtyTiles::new(width, height);
+        self.recount();
+        self.last = (self.last.0 + left, self.last.1 + top);
+        // Recorded indices refer to the old layout.
+        self.start_step();
+        self.rebuild_frontier();
+    }
+
+    /// Resets `cells` to uncollapsed and reseeds the generator, so the next epochs
+    /// regrow just that region from the collapsed cells around it. Cells outside
+    /// the field are ignored.
+    pub fn reroll(&mut self, cells: &[Index], seed: u64) -> ChangeSet {
+        let (width, height) = (self.width, self.height);
+        for &p in cells.iter().filter(|&&(x, y)| x < width && y < height) {
+            let idx = self.idx(p);
+            if self.cells.is_collapsed(idx) {
+                self.set_cell(idx, false, Pixel::new(), Origin::default());
+            }
         }
+        self.reseed(seed);
+        self.rebuild_frontier();
+        if self.frontier.is_empty() && !cells.is_empty() {
+            self.init();
+        }
+        self.take_changes(self.epoch_idx)
+    }
+
+    /// Continues growth from the current cells with a different seed.
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng.reseed(seed);
+    }
+
+    /// Resets the frontier to every collapsed cell that still borders an uncollapsed one.
+    /// They are queued in collapse order, the order growth pushes them in, so growth
+    /// after an undo or redo, with its `GrowthState` restored, carries on exactly as
+    /// it did the first time.
+    pub fn rebuild_frontier(&mut self) {
+        let mut cells: Vec<usize> = (0..self.len())
+            .filter(|&i| self.cells.is_collapsed(i) && self.num_blank[i] > 0)
+            .collect();
+        cells.sort_by_key(|&i| self.cells.origin(i).order);
+
+        self.frontier.reset(self.len());
+        for i in cells {
+            self.frontier.push(i);
+        }
+
+        if let Some(i) = self.frontier.first() {
+            self.last = self.pos(i);
+        }
+    }
+
+    pub fn frontier(&self) -> &Frontier {
+        &self.frontier
     }
 
     pub fn len(&self) -> usize {
-        self.data.len()
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    pub fn idx(&self, (x, y): Index) -> usize {
+        y * self.width + x
+    }
+
+    pub fn pos(&self, idx: usize) -> Index {
+        (idx % self.width, idx / self.width)
     }
 
     pub fn init(&mut self) {
-        let x = Rand::gen_rangei32(0..(self.dim + 1) as i32) as usize;
-        let y = Rand::gen_rangei32(0..(self.dim + 1) as i32) as usize;
-
-        let idx = x * self.dim + y;
-        let d = &mut self.data[idx];
-        d.collapsed = true;
-        d.px = Pixel::random();
-
-        self.visited.push((x, y));
-    }
-
-    pub fn gen_neighbours(len: usize, dim: usize) -> Box<[Box<[Index]>]> {
-        let mut neighs = Vec::with_capacity(len);
-        for x in 0..dim as i32 {
-            for y in 0..dim as i32 {
-                // let idx = x * dim as i32 + y;
-                let iter = (-1..=1)
-                    .map(|x1| {
-                        (-1..=1).filter_map(move |y1| {
-                            let xn = x as i32 + x1;
-                            let yn = y as i32 + y1;
-                            if (xn < 0 || yn < 0)
-                                || (xn >= dim as i32 || yn >= dim as i32)
-                                || (x == xn && y == yn)
-                            {
-                                return None;
-                            }
-                            Some((xn as usize, yn as usize))
-                        })
-                    })
-                    .flatten()
-                    .collect::<Box<[Index]>>();
-                neighs.push(iter);
-            }
+        let x = self.rng.gen_rangei32(0..(self.width + 1) as i32) as usize;
+        let y = self.rng.gen_rangei32(0..(self.height + 1) as i32) as usize;
+
+        let idx = self.idx((x, y));
+        let px = Pixel::random(&self.rng);
+        let origin = self.next_origin(0);
+        self.set_cell(idx, true, px, origin);
+        self.last = (x, y);
+    }
+
+    /// New colour for `cur_idx` from its collapsed neighbours, along with which of
+    /// them went into it as `Origin::sources` bits.
+    fn gen_value(&self, cur_idx: usize) -> (Hsl, u8) {
+        let (mean, sources) = self.mean_value(cur_idx).unwrap();
+        (jitter(mean, &self.variation, &self.rng), sources)
+    }
+
+    fn mean_value(&self, idx: usize) -> Option<(Hsl, u8)> {
+        let cells = &self.cells;
+        mean_value(self.topology.as_ref(), self.width, idx, |n| {
+            cells.is_collapsed(n).then(|| cells.hsl(n))
+        })
+    }
+
+    /// Colour an uncollapsed cell is most likely to get from its current neighbours,
+    /// each channel in the middle of its variation range.
+    pub fn expected(&self, idx: usize) -> Option<Rgba> {
+        let ([h, _, l], _) = self.mean_value(idx)?;
+        let mid = |(lo, hi): (f64, f64)| (lo + hi) / 2.0;
+        let Variation {
+            hue,
+            saturation,
+            lightness,
+        } = self.variation;
+        Some(Pixel::hsl2rgb(
+            h + mid(hue),
+            mid(saturation),
+            l + mid(lightness),
+        ))
+    }
+
+    /// Collapses `idx` to `col` in the current epoch, recording the change in the journal.
+    fn collapse(&mut self, idx: usize, (col, sources): (Hsl, u8)) {
+        let mut px = Pixel::new();
+        px.set_data(PixelType::Hsl(col));
+        let origin = self.next_origin(sources);
+        self.set_cell(idx, true, px, origin);
+    }
+
+    /// Origin for a cell collapsed now from `sources`.
+    fn next_origin(&mut self, sources: u8) -> Origin {
+        self.collapses += 1;
+        Origin {
+            epoch: Some(self.epoch_idx as u32),
+            order: self.collapses,
+            sources,
         }
+    }
 
-        neighs.into_boxed_slice()
+    /// Every cell collapsed through here joins the frontier.
+    fn set_cell(&mut self, idx: usize, collapsed: bool, px: Pixel, origin: Origin) {
+        if collapsed {
+            self.frontier.push(idx);
+        }
+        let before = CellState::of(&self.cells, idx);
+        self.write(idx, collapsed, &px, origin);
+        self.journal.push(CellChange {
+            idx: idx as u32,
+            before,
+            after: CellState::of(&self.cells, idx),
+        });
     }
 
-    fn gen_value(&self, (x, y): Index) -> Hsl {
-        let mut cols = vec![];
-        let cur_idx = x * self.dim + y;
-        for (_x, _y) in self.neighbours[cur_idx].iter() {
-            let idx = _x * self.dim + _y;
-            let cell = &self.data[idx];
-            if cell.collapsed {
-                cols.push(cell.px.hsl);
+    /// The single place cells are written, keeps the neighbour counters in sync.
+    /// Neighbours are marked dirty too since their expected colour changes.
+    fn write(&mut self, idx: usize, collapsed: bool, px: &Pixel, origin: Origin) {
+        if collapsed != self.cells.is_collapsed(idx) {
+            for n in self.topology.neighbours(idx).iter() {
+                match collapsed {
+                    true => self.num_blank[n] -= 1,
+                    false => self.num_blank[n] += 1,
+                }
+                self.dirty.mark(n % self.width, n / self.width);
+            }
+            match collapsed {
+                true => self.remaining -= 1,
+                false => self.remaining += 1,
             }
         }
-        // let cols2 = cols.clone();
-        let cnt = cols.len() as f64;
-        let sum = cols
-            .into_iter()
-            .reduce(|mut acc, v| {
-                acc[0] += v[0];
-                acc[2] += v[2];
-                acc
-            })
-            .unwrap();
-
-        let new = [sum[0] / cnt, sum[1], sum[2] / cnt];
-
-        let h_rang = BASIC_RANGES.0 .0..BASIC_RANGES.0 .1;
-        let h = new[0] + Rand::gen_rangei32(h_rang);
-        let l = new[2] + Rand::gen_rangef64(BASIC_RANGES.1 .0, BASIC_RANGES.1 .1);
-
-        // log!(format!("cols.len: {:?}",cnt));
-        // log!(format!("Cols: {:#?}",cols2));
-        // log!(format!("Hsl: {:?}",new));
-        // log!(format!("Rgba: {:?}",Pixel::hsl2rgb(h, 1.0, l)));
-        // log!("--------------------------------------------------");
-        // self.data[idx].px.rgba = Pixel::hsl2rgb(h, 1.0, l);
-        // self.data[cur_idx].px.set_data(PixelType::HSL([h, 1.0, l]));
-        // self.data[cur_idx].collapsed = true;
-        [h, 1.0, l]
-    }
-
-    pub fn epoch(&mut self) {
-        log!("Visited len: ", self.visited.len());
-        for (_x, _y) in self.visited.clone() {
-            for (x, y) in self.neighbours[_x * self.dim + _y].iter() {
-                let idx = x * self.dim + y;
-                if !self.visited.contains(&(*x, *y)) {
-                    self.visited.push((*x, *y));
-                }
+        self.cells.set(idx, collapsed, px);
+        self.cells.set_origin(idx, origin);
+        self.dirty.mark(idx % self.width, idx / self.width);
+    }
 
-                if !self.data[idx].collapsed {
-                    let col = self.gen_value((*x, *y));
-                    self.data[idx].collapsed = true;
-                    self.data[idx].px.set_data(PixelType::HSL(col));
-                }
-                // return;
+    /// Regions written since the last call, for redrawing only what changed.
+    pub fn take_dirty(&mut self) -> Vec<Rect> {
+        self.dirty.take_rects()
+    }
+
+    /// RGBA bytes of `rect`, row-major, ready for an `ImageData` of the same size.
+    pub fn rgba_region(&self, rect: Rect) -> Vec<u8> {
+        rect.crop_rgba(self.cells.rgba_bytes(), self.width)
+    }
+
+    /// Drains the journal into a change set for a step that started at `epoch`.
+    fn take_changes(&mut self, epoch: usize) -> ChangeSet {
+        let end = self.growth_state();
+        ChangeSet {
+            epoch,
+            epochs: self.epoch_idx - epoch,
+            changes: std::mem::take(&mut self.journal),
+            frontier: self.frontier.len(),
+            start: std::mem::replace(&mut self.step_start, end),
+            end,
+        }
+    }
+
+    /// Drops the journal, the next step starts from here.
+    fn start_step(&mut self) {
+        self.journal.clear();
+        self.step_start = self.growth_state();
+    }
+
+    pub fn growth_state(&self) -> GrowthState {
+        GrowthState {
+            seed: self.seed,
+            rng: self.rng.state(),
+            collapses: self.collapses,
+            last: self.last,
+        }
+    }
+
+    /// Continues growth from `state`, e.g. one recorded in a `ChangeSet`.
+    pub fn restore(&mut self, state: GrowthState) {
+        self.seed = state.seed;
+        self.rng.reseed(state.rng);
+        self.collapses = state.collapses;
+        self.last = state.last;
+        self.step_start = state;
+    }
+
+    /// Writes back recorded changes without journaling them, `undo` restores the
+    /// `before` states in reverse order. Call `rebuild_frontier` once done.
+    pub fn apply_changes(&mut self, changes: &[CellChange], undo: bool) {
+        match undo {
+            true => changes.iter().rev().for_each(|c| self.write_state(c.idx, c.before)),
+            false => changes.iter().for_each(|c| self.write_state(c.idx, c.after)),
+        }
+    }
+
+    /// Writes cell states in order without journaling them, e.g. the ones of an
+    /// `Update::Changes`.
+    pub fn apply_writes(&mut self, writes: &[(u32, CellState)]) {
+        writes.iter().for_each(|&(idx, state)| self.write_state(idx, state));
+    }
+
+    fn write_state(&mut self, idx: u32, state: CellState) {
+        let px = Pixel {
+            rgba: state.rgba,
+            hsl: state.hsl.map(|v| v as f64),
+        };
+        self.write(idx as usize, state.collapsed, &px, state.origin);
+    }
+
+    /// Collapses every uncollapsed neighbour of `cur`; they join the frontier.
+    fn grow_from(&mut self, cur: usize) {
+        if !self.cells.is_collapsed(cur) {
+            return;
+        }
+        for idx in self.topology.neighbours(cur).iter() {
+            if !self.cells.is_collapsed(idx) {
+                let value = self.gen_value(idx);
+                self.collapse(idx, value);
             }
-            let idx = self
-                .visited
-                .iter()
-                .position(|idx| *idx == (_x, _y))
-                .unwrap();
-            self.visited.remove(idx);
         }
     }
 
-    pub fn epoch2(&mut self) {
-        let (x, y) = self.last;
-        let idx = x * self.dim + y;
-        let neighs = &self.neighbours[idx];
-        let i = Rand::gen_rangei32(0..neighs.len() as i32) as usize;
+    /// Everything the inspector shows about the cell at `idx`.
+    pub fn inspect(&self, idx: usize) -> (Pixel, bool, Origin) {
+        (
+            self.cells.pixel(idx),
+            self.cells.is_collapsed(idx),
+            self.cells.origin(idx),
+        )
+    }
 
-        for (n, (x, y)) in neighs.iter().enumerate() {
-            let idx = x * self.dim + y;
+    pub fn epoch(&mut self) -> ChangeSet {
+        for cur in self.frontier.take() {
+            self.grow_from(cur as usize);
+        }
+        self.epoch_idx += 1;
+        self.take_changes(self.epoch_idx - 1)
+    }
 
-            if !self.data[idx].collapsed {
-                let col = self.gen_value((*x, *y));
-                self.data[idx].collapsed = true;
-                self.data[idx].px.set_data(PixelType::HSL(col));
+    /// Collapses the neighbours of the last cell and walks on to one of them, picked
+    /// uniformly. Cells the walk has surrounded leave the frontier, so the frontier
+    /// stop conditions can end it.
+    pub fn epoch2(&mut self) -> ChangeSet {
+        let cur = self.idx(self.last);
+        let neighs = self.topology.neighbours(cur);
+        // a cell without neighbours, e.g. on a 1x1 field, has nowhere to go
+        let i = (self.rng.random() * neighs.len() as f64) as usize;
+
+        for (n, idx) in neighs.iter().enumerate() {
+            if !self.cells.is_collapsed(idx) {
+                let value = self.gen_value(idx);
+                self.collapse(idx, value);
             }
 
             if n == i {
-                self.last = (*x, *y);
+                self.last = self.pos(idx);
             }
         }
+
+        self.prune_frontier();
+
+        self.epoch_idx += 1;
+        self.take_changes(self.epoch_idx - 1)
+    }
+
+    /// Whether all neighbours of `p` are collapsed.
+    pub fn is_blank(&self, p: Index) -> bool {
+        self.num_blank[self.idx(p)] == 0
+    }
+
+    /// Number of uncollapsed neighbours of `idx`.
+    pub fn blank_neighbours(&self, idx: usize) -> usize {
+        self.num_blank[idx] as usize
+    }
+
+    /// Number of cells that are still uncollapsed.
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.remaining == 0
+    }
+
+    pub fn variation(&self) -> &Variation {
+        &self.variation
+    }
+
+    /// Applies to cells generated from now on.
+    pub fn set_variation(&mut self, variation: Variation) {
+        self.variation = variation;
+    }
+
+    pub fn stop_conditions(&self) -> &[StopCondition] {
+        &self.stop_conditions
+    }
+
+    pub fn set_stop_conditions(&mut self, conditions: Vec<StopCondition>) {
+        self.stop_conditions = conditions;
+    }
+
+    /// The first stop condition met after `elapsed_ms` of growth, if any. Growth
+    /// always ends once the field is complete or nothing can grow, whatever the
+    /// conditions are.
+    pub fn finished(&self, elapsed_ms: f64) -> Option<Finished> {
+        let conditions = self.stop_conditions.iter().chain(&StopCondition::DEFAULT);
+        self.first_met(conditions, elapsed_ms)
+    }
+
+    /// Like `finished`, but only checks whether the field is complete or can't grow.
+    pub fn completed(&self) -> Option<Finished> {
+        self.first_met(StopCondition::DEFAULT.iter(), 0.0)
+    }
+
+    fn first_met<'a>(
+        &self,
+        mut conditions: impl Iterator<Item = &'a StopCondition>,
+        elapsed_ms: f64,
+    ) -> Option<Finished> {
+        let condition = conditions.find(|c| c.is_met(self, elapsed_ms))?;
+        Some(Finished {
+            condition: *condition,
+            epoch: self.epoch_idx,
+        })
+    }
+
+    pub fn topology(&self) -> &dyn Topology {
+        self.topology.as_ref()
+    }
+
+    /// Swaps the neighbourhood, `topology` must match the field size.
+    pub fn set_topology(&mut self, topology: Box<dyn Topology>) {
+        self.topology = topology;
+        self.recount();
+        self.rebuild_frontier();
+    }
+
+    /// Like `epoch`, but also drops cells from the frontier that have become
+    /// surrounded by collapsed cells in the meantime.
+    pub fn epoch3(&mut self) -> ChangeSet {
+        for cur in self.frontier.take() {
+            self.grow_from(cur as usize);
+        }
+
+        self.prune_frontier();
+
+        self.epoch_idx += 1;
+        self.take_changes(self.epoch_idx - 1)
     }
 
-    pub fn is_blank(&self, (x, y): Index) -> bool {
-        for (x, y) in self.neighbours[x * self.dim + y].iter() {
-            if !self.data[x * self.dim + y].collapsed {
-                return false;
+    /// Drops cells from the frontier that have no uncollapsed neighbours left.
+    fn prune_frontier(&mut self) {
+        let mut frontier = std::mem::take(&mut self.frontier);
+        frontier.retain(|i| self.num_blank[i] > 0);
+        self.frontier = frontier;
+    }
+
+    /// Parallel counterpart of `epoch3`, run on this thread. The result only depends
+    /// on the seed, not on how the jobs are spread over workers or how many epochs
+    /// they run at once.
+    pub fn epoch_parallel(&mut self) -> ChangeSet {
+        let results: Vec<_> = parallel::jobs(self, 1).iter().map(parallel::run).collect();
+        self.apply_tiles(&results, 0)
+    }
+
+    /// Merges epoch `epoch` of the results of all jobs from `parallel::jobs`, which
+    /// must be applied in order from the first. Cells are collapsed in index order so
+    /// `Origin::order` doesn't depend on arrival order.
+    pub fn apply_tiles(&mut self, results: &[TileResult], epoch: usize) -> ChangeSet {
+        let mut cells: Vec<_> = results
+            .iter()
+            .filter_map(|r| r.cells.0.get(epoch))
+            .flatten()
+            .copied()
+            .collect();
+        cells.sort_unstable_by_key(|c| c.0);
+        for (idx, col, sources) in cells {
+            if !self.cells.is_collapsed(idx as usize) {
+                self.collapse(idx as usize, (col, sources));
             }
         }
-        true
-    }
 
-    pub fn epoch3(&mut self) {
-        // log!("Visited len: ", self.visited.len());
+        self.prune_frontier();
 
-        for (_x, _y) in self.visited.clone() {
-            for (x, y) in self.neighbours[_x * self.dim + _y].iter() {
-                let idx = x * self.dim + y;
-                if !self.visited.contains(&(*x, *y)){
-                    self.visited.push((*x, *y));
-                }
+        self.epoch_idx += 1;
+        self.take_changes(self.epoch_idx - 1)
+    }
 
-                if !self.data[idx].collapsed {
-                    let col = self.gen_value((*x, *y));
-                    self.data[idx].collapsed = true;
-                    self.data[idx].px.set_data(PixelType::HSL(col));
+    /// Collapses the same cells as `epoch3`, most-constrained first: cells with the
+    /// fewest uncollapsed neighbours, i.e. the most collapsed ones to draw from, go
+    /// first. This fills in concave corners before pushing the front outwards.
+    pub fn epoch_constrained(&mut self) -> ChangeSet {
+        let mut buckets: [Vec<u32>; MAX_NEIGHBOURS + 1] = Default::default();
+        for cur in self.frontier.take() {
+            for idx in self.topology.neighbours(cur as usize).iter() {
+                if !self.cells.is_collapsed(idx) {
+                    buckets[self.num_blank[idx] as usize].push(idx as u32);
                 }
-                // return;
             }
-            let idx = self
-                .visited
-                .iter()
-                .position(|idx| *idx == (_x, _y))
-                .unwrap();
-            self.visited.remove(idx);
         }
 
-        let l = self.visited.len();
-        if l > 200 {
-            self.visited = self
-                .visited
-                .iter()
-                .filter_map(|x| {
-                    if self.is_blank(*x) {
-                        return None;
-                    };
-                    Some(*x)
-                })
-                .collect();
+        for idx in buckets.into_iter().flatten() {
+            let idx = idx as usize;
+            if !self.cells.is_collapsed(idx) {
+                let value = self.gen_value(idx);
+                self.collapse(idx, value);
+            }
         }
+
+        self.prune_frontier();
+
+        self.epoch_idx += 1;
+        self.take_changes(self.epoch_idx - 1)
     }
 }
@@ -1,25 +1,18 @@
-use serde::{Deserialize, Serialize};
-use yew_agent::{HandlerId, Public, WorkerLink};
+use yew_agent::{HandlerId, Private, WorkerLink};
 
+use crate::parallel::{self, TileJob, TileResult};
+
+/// Generates the tiles of parallel epochs off the main thread. Private, so every
+/// bridge gets a web worker of its own.
 pub struct Worker {
     link: WorkerLink<Self>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct WorkerInput {
-    pub n: u32,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct WorkerOutput {
-    pub value: u32,
-}
-
 impl yew_agent::Worker for Worker {
-    type Input = WorkerInput;
+    type Input = TileJob;
     type Message = ();
-    type Output = WorkerOutput;
-    type Reach = Public<Self>;
+    type Output = TileResult;
+    type Reach = Private<Self>;
 
     fn create(link: WorkerLink<Self>) -> Self {
         Self { link }
@@ -29,27 +22,14 @@ impl yew_agent::Worker for Worker {
         // no messaging
     }
 
-    fn handle_input(&mut self, msg: Self::Input, id: HandlerId) {
+    fn handle_input(&mut self, job: Self::Input, id: HandlerId) {
         // this runs in a web worker
         // and does not block the main
         // browser thread!
-
-        let n = msg.n;
-
-        fn fib(n: u32) -> u32 {
-            if n <= 1 {
-                1
-            } else {
-                fib(n - 1) + fib(n - 2)
-            }
-        }
-
-        let output = Self::Output { value: fib(n) };
-
-        self.link.respond(id, output);
+        self.link.respond(id, parallel::run(&job));
     }
 
     fn name_of_resource() -> &'static str {
         "worker.js"
     }
-}
\ No newline at end of file
+}